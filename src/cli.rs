@@ -3,9 +3,13 @@ use std::ffi::OsString;
 use std::path::PathBuf;
 use std::str::FromStr;
 
+use chrono::{DateTime, NaiveDate, TimeDelta, Utc};
 use clap::{Parser, ValueEnum};
+use globset::Glob;
 use log::LevelFilter;
 
+use crate::crustasyncfs::googledrive::{DuplicatePolicy, ExportFormat, WorkspaceDocPolicy};
+use crate::diff::ConflictPolicy;
 use crate::enum_str;
 
 enum_str! {
@@ -24,12 +28,94 @@ impl LogLevel {
     }
 }
 
+#[derive(ValueEnum, Debug, Clone, PartialEq)]
+pub enum SyncMode {
+    OneWay,
+    TwoWay,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum OnConflict {
+    Newest,
+    KeepSource,
+    KeepDestination,
+    KeepBoth,
+    Abort,
+}
+
+impl OnConflict {
+    pub fn policy(&self) -> ConflictPolicy {
+        match self {
+            OnConflict::Newest => ConflictPolicy::Newest,
+            OnConflict::KeepSource => ConflictPolicy::KeepSource,
+            OnConflict::KeepDestination => ConflictPolicy::KeepDestination,
+            OnConflict::KeepBoth => ConflictPolicy::KeepBoth,
+            OnConflict::Abort => ConflictPolicy::Abort,
+        }
+    }
+}
+
 fn default_cfg_path() -> OsString {
     // TODO support window
     let path = PathBuf::from_iter([env::var("HOME").unwrap().as_str(), ".config/crustasync"]);
     path.into_os_string()
 }
 
+// Bytes, or a number with a K, M, G or T suffix, in powers of 1024, e.g. `100M`
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let number = number
+        .parse::<u64>()
+        .map_err(|_| format!("expect a size like 1024, 500K or 100M, found `{value}`"))?;
+    let shift = match unit
+        .trim()
+        .to_uppercase()
+        .trim_end_matches("IB")
+        .trim_end_matches('B')
+    {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("unknown size unit `{unit}`, expect K, M, G or T")),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("`{value}` is too big"))
+}
+
+// An age like `30m`, `12h` or `7d`, or a date like `2024-01-31` or `2024-01-31T12:00:00Z`
+fn parse_newer_than(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.to_utc());
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+    }
+
+    let unit_start = value.char_indices().last().map_or(0, |(i, _)| i);
+    let (number, unit) = value.split_at(unit_start);
+    let age = number.parse::<i64>().ok().and_then(|n| match unit {
+        "s" => TimeDelta::try_seconds(n),
+        "m" => TimeDelta::try_minutes(n),
+        "h" => TimeDelta::try_hours(n),
+        "d" => TimeDelta::try_days(n),
+        "w" => TimeDelta::try_weeks(n),
+        _ => None,
+    });
+    match age {
+        Some(age) => Ok(Utc::now() - age),
+        None => Err(format!(
+            "expect an age like 12h or 7d, or a date like 2024-01-31, found `{value}`"
+        )),
+    }
+}
+
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct CLIOption {
@@ -38,7 +124,11 @@ pub struct CLIOption {
         index = 1,
         help = "Source directory.\
                 \nCan be relative or absolute local path.\
-                \nUse prefix `gd:` to indicate a GoogleDrive directory"
+                \nUse prefix `gd:` to indicate a GoogleDrive directory, e.g. `gd:/path`, or `gd:@Team/path` in shared drive Team\
+                \nUse prefix `gd-id:` to indicate a GoogleDrive directory by id, e.g. `gd-id:1a2B3c`\
+                \nUse prefix `s3:` to indicate a S3 bucket & key prefix, e.g. `s3:bucket/path`\
+                \nUse prefix `sftp:` to indicate a directory on a SSH server, e.g. `sftp:user@host:/path`\
+                \nUse prefix `dav:` to indicate a WebDAV directory, e.g. `dav:https://host/remote.php/dav/files/user/path`"
     )]
     pub src_dir: String,
 
@@ -52,9 +142,276 @@ pub struct CLIOption {
     #[arg(long, action)]
     pub dry_run: bool,
 
+    #[arg(
+        long,
+        action,
+        help = "Build trees from scratch: hash every local file again, instead of reusing the hashes\
+                \ncached in .crustasync-hashes for files whose size, modification time & inode are unchanged,\
+                \nand list every GoogleDrive folder instead of fetching the changes since the last run"
+    )]
+    pub full_rehash: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "one-way",
+        help = "one-way: make DST_DIR look like SRC_DIR\
+                \ntwo-way: merge changes from both sides since the last sync"
+    )]
+    pub mode: SyncMode,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "keep-both",
+        help = "How to resolve files changed on both sides in two-way mode"
+    )]
+    pub conflict_policy: OnConflict,
+
+    #[arg(
+        long,
+        default_value = "16",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Max number of files to transfer, move or delete at once.\
+                \nThe per-backend limits below apply too"
+    )]
+    pub concurrency: u32,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        value_parser = Glob::new,
+        help = "Only sync files matching one of these globs, relative to SRC_DIR & DST_DIR,\
+                \ne.g. `*.pdf` or `photos/**`. Can be repeated"
+    )]
+    pub include: Vec<Glob>,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        value_parser = Glob::new,
+        help = "Leave out files & directories matching one of these globs, e.g. `*.tmp` or `**/build`.\
+                \nCan be repeated, takes precedence over --include"
+    )]
+    pub exclude: Vec<Glob>,
+
+    #[arg(
+        long,
+        value_parser = parse_size,
+        help = "Only sync files of at most this size, in bytes or with a K, M, G, T suffix, e.g. `100M`"
+    )]
+    pub max_size: Option<u64>,
+
+    #[arg(
+        long,
+        value_parser = parse_size,
+        help = "Only sync files of at least this size, same format as --max-size"
+    )]
+    pub min_size: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "AGE_OR_DATE",
+        value_parser = parse_newer_than,
+        help = "Only sync files modified in the last 30m, 12h, 7d, 2w...\
+                \nor since a date like 2024-01-31 or 2024-01-31T12:00:00Z"
+    )]
+    pub newer_than: Option<DateTime<Utc>>,
+
     #[arg(long, value_enum, default_value = "info")]
     pub log_level: LogLevel,
 
     #[arg(long, short, default_value = default_cfg_path())]
     pub config_dir: PathBuf,
+
+    #[arg(
+        long,
+        default_value = "8",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Size in MiB of the chunks uploaded to GoogleDrive.\
+                \nAn interrupted upload resumes from the last chunk received"
+    )]
+    pub gd_chunk_size: u64,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "skip",
+        help = "What to do with Google Docs, Sheets, Slides...\
+                \nskip: ignore them\
+                \nexport: sync a copy exported in GD_EXPORT_FORMAT, read-only on GoogleDrive\
+                \nopaque: ignore them & never delete or overwrite them"
+    )]
+    pub gd_docs: WorkspaceDocPolicy,
+
+    #[arg(long, value_enum, default_value = "office")]
+    pub gd_export_format: ExportFormat,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "error",
+        help = "What to do when several files of a GoogleDrive folder have the same name\
+                \nerror: stop the sync\
+                \nnewest: only sync the most recently modified one\
+                \nrename: sync all of them, as `name (duplicate).ext`, `name (duplicate 2).ext`..."
+    )]
+    pub gd_duplicates: DuplicatePolicy,
+
+    #[arg(
+        long,
+        action,
+        help = "Delete GoogleDrive files permanently instead of moving them to the trash"
+    )]
+    pub gd_permanent_delete: bool,
+
+    #[arg(
+        long,
+        default_value = "4",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Max number of requests to GoogleDrive at once, when listing & syncing files"
+    )]
+    pub gd_concurrency: u32,
+
+    #[arg(
+        long,
+        env = "AWS_ENDPOINT_URL",
+        help = "S3 endpoint, for S3-compatible storage such as MinIO.\
+                \nCredentials are read from AWS_ACCESS_KEY_ID & AWS_SECRET_ACCESS_KEY"
+    )]
+    pub s3_endpoint: Option<String>,
+
+    #[arg(long, env = "AWS_REGION", default_value = "us-east-1")]
+    pub s3_region: String,
+
+    #[arg(
+        long,
+        default_value = "16",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Max number of requests to S3 at once, when listing & syncing files"
+    )]
+    pub s3_concurrency: u32,
+
+    #[arg(
+        long,
+        help = "Private key file for SFTP.\
+                \nWithout it, ssh-agent and the default ssh config & keys are used"
+    )]
+    pub sftp_key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        action,
+        help = "Trust the host key of a SFTP server not in known_hosts yet, and add it there.\
+                \nWithout it, unknown servers are refused"
+    )]
+    pub sftp_accept_new_host: bool,
+
+    #[arg(
+        long,
+        action,
+        help = "Hash files on the SFTP server with `sha256sum` instead of downloading them.\
+                \nFalls back to downloading if the command is not available"
+    )]
+    pub sftp_remote_hash: bool,
+
+    #[arg(
+        long,
+        default_value = "4",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Max number of requests to the SFTP server at once, when listing & syncing files"
+    )]
+    pub sftp_concurrency: u32,
+
+    #[arg(
+        long,
+        env = "DAV_USERNAME",
+        help = "WebDAV username, ignored if the location contains credentials"
+    )]
+    pub dav_username: Option<String>,
+
+    #[arg(long, env = "DAV_PASSWORD", hide_env_values = true)]
+    pub dav_password: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "4",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "Max number of requests to the WebDAV server at once, when listing & syncing files"
+    )]
+    pub dav_concurrency: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn parse_size_reads_unit_suffixes() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("500K"), Ok(500 << 10));
+        assert_eq!(parse_size("100M"), Ok(100 << 20));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert_eq!(parse_size("3T"), Ok(3 << 40));
+        assert_eq!(parse_size("10MB"), Ok(10 << 20));
+        assert_eq!(parse_size("10 MiB"), Ok(10 << 20));
+        assert_eq!(parse_size("0"), Ok(0));
+    }
+
+    #[test]
+    fn parse_size_rejects_bad_sizes() {
+        for value in ["", "M", "-1", "1.5M", "10X", "10 KB B"] {
+            assert!(parse_size(value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn parse_size_rejects_overflows() {
+        assert_eq!(parse_size("16777215T"), Ok(16777215 << 40));
+        assert!(parse_size("16777216T").is_err());
+        assert_eq!(parse_size("18446744073709551615"), Ok(u64::MAX));
+        assert!(parse_size("18446744073709551616").is_err());
+    }
+
+    #[test]
+    fn parse_newer_than_reads_dates() {
+        assert_eq!(
+            parse_newer_than("2024-01-31"),
+            Ok(Utc.with_ymd_and_hms(2024, 1, 31, 0, 0, 0).unwrap())
+        );
+        assert_eq!(
+            parse_newer_than("2024-01-31T12:00:00Z"),
+            Ok(Utc.with_ymd_and_hms(2024, 1, 31, 12, 0, 0).unwrap())
+        );
+        assert_eq!(
+            parse_newer_than("2024-01-31T12:00:00+02:00"),
+            Ok(Utc.with_ymd_and_hms(2024, 1, 31, 10, 0, 0).unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_newer_than_reads_ages() {
+        for (value, age) in [
+            ("45s", TimeDelta::seconds(45)),
+            ("30m", TimeDelta::minutes(30)),
+            ("12h", TimeDelta::hours(12)),
+            ("7d", TimeDelta::days(7)),
+            ("2w", TimeDelta::weeks(2)),
+        ] {
+            let before = Utc::now();
+            let time = parse_newer_than(value).unwrap();
+            assert!(before - age <= time && time <= Utc::now() - age, "{value}");
+        }
+    }
+
+    #[test]
+    fn parse_newer_than_rejects_bad_values() {
+        for value in ["", "h", "7", "7y", "7µ", "yesterday", "2024-13-01"] {
+            assert!(parse_newer_than(value).is_err(), "{value}");
+        }
+        // too old for a date
+        assert!(parse_newer_than(&format!("{}d", i64::MAX)).is_err());
+    }
 }
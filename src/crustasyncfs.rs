@@ -7,17 +7,36 @@ use crate::error::Result;
 pub mod base;
 pub mod googledrive;
 pub mod local;
+pub mod memory;
+pub mod s3;
+pub mod sftp;
+pub mod webdav;
 
 pub async fn fs_from_location_str(
     location: &str,
     opt: &CLIOption,
 ) -> Result<Arc<dyn FileSystem + Send + Sync>> {
-    if location.starts_with("gd:") {
+    if location.starts_with("gd-id:") {
+        let folder_id = location.trim_start_matches("gd-id:");
+        let fs = googledrive::GoogleDriveFileSystem::from_folder_id(opt, folder_id).await?;
+        Ok(Arc::new(fs))
+    } else if location.starts_with("gd:") {
         let path_buf = PathBuf::from(location.trim_start_matches("gd:"));
         let fs = googledrive::GoogleDriveFileSystem::new(opt, &path_buf).await?;
         Ok(Arc::new(fs))
+    } else if location.starts_with("s3:") {
+        let fs = s3::S3FileSystem::new(opt, location.trim_start_matches("s3:"))?;
+        Ok(Arc::new(fs))
+    } else if location.starts_with("sftp:") {
+        let fs = sftp::SftpFileSystem::new(opt, location.trim_start_matches("sftp:")).await?;
+        Ok(Arc::new(fs))
+    } else if location.starts_with("dav:") {
+        let fs = webdav::WebDavFileSystem::new(opt, location.trim_start_matches("dav:")).await?;
+        Ok(Arc::new(fs))
     } else {
-        let fs = local::LocalFileSystem::new(location.as_ref()).await?;
+        let fs = local::LocalFileSystem::new(location.as_ref())
+            .await?
+            .with_full_rehash(opt.full_rehash);
         Ok(Arc::new(fs))
     }
 }
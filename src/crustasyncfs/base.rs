@@ -1,11 +1,19 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Cursor;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use log::debug;
+use futures::{stream, Future, StreamExt, TryStreamExt};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 use serde_json as serde_lib;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::{Semaphore, SemaphorePermit};
 
 use crate::error::Result;
 
@@ -30,6 +38,12 @@ pub struct Node {
     pub updated_at: DateTime<Utc>,
     pub content_hash: ContentHash,
     pub children: Vec<Node>,
+    // in bytes, for dirs it's the total size of their descendants
+    #[serde(default)]
+    pub size: u64,
+    // only known for local files
+    #[serde(default)]
+    pub inode: Option<u64>,
 }
 
 impl Node {
@@ -46,6 +60,48 @@ impl Node {
             NodeType::Directory => true,
         }
     }
+
+    // Prefix file with ffff, dir with dddd
+    // This is to distinguish between empty files and empty dirs
+    pub fn node_hash(&self) -> ContentHash {
+        let mut hash = self.content_hash;
+        if self.is_file() {
+            hash[0..4].fill(b'f');
+        } else {
+            hash[0..4].fill(b'd');
+        }
+        hash
+    }
+
+    // Directory hash is computed from the names and node hashes of its children
+    // Children are sorted by lowercase name so that the result is deterministic
+    pub fn new_dir(
+        name: String,
+        path: PathBuf,
+        updated_at: DateTime<Utc>,
+        mut children: Vec<Node>,
+    ) -> Node {
+        let mut hasher = Sha256::new();
+
+        children.sort_by_key(|node| node.name.clone().to_lowercase());
+
+        children.iter().for_each(|node| {
+            let filename = node.name.as_bytes();
+            hasher.update(filename);
+            hasher.update(node.node_hash());
+        });
+
+        Node {
+            node_type: NodeType::Directory,
+            name,
+            path,
+            updated_at,
+            content_hash: hasher.finalize().into(),
+            size: children.iter().map(|node| node.size).sum(),
+            inode: None,
+            children,
+        }
+    }
 }
 
 pub struct NodeIterator<'a> {
@@ -86,14 +142,160 @@ impl<'a> IntoIterator for &'a Node {
 }
 // endregion
 
+// ------------------------------
+// region Ignore
+// ------------------------------
+
+pub const CRUSTASYNC_IGNORE_FILE: &str = ".crustasyncignore";
+
+// Rules of the .crustasyncignore files of a tree, in gitignore syntax.
+// The rules of a file apply under its directory, deeper files take precedence
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreFilter {
+    // by path of the directory containing the ignore file
+    rules: BTreeMap<PathBuf, Gitignore>,
+}
+
+impl IgnoreFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Add the rules of the ignore file in dir, invalid lines are skipped
+    pub fn add(&mut self, dir: &Path, content: &str) {
+        let ignore_file = dir.join(CRUSTASYNC_IGNORE_FILE);
+        let mut builder = GitignoreBuilder::new("");
+        for line in content.lines() {
+            if let Err(e) = builder.add_line(Some(ignore_file.clone()), line) {
+                warn!("Skipping rule of {}: {e}", ignore_file.display());
+            }
+        }
+        match builder.build() {
+            Ok(gitignore) => {
+                self.rules.insert(dir.to_path_buf(), gitignore);
+            }
+            Err(e) => warn!("Skipping {}: {e}", ignore_file.display()),
+        }
+    }
+
+    // Read the ignore files of a tree built without its ignore rules
+    pub async fn from_tree(fs: &(impl FileSystem + ?Sized), tree: &Node) -> Result<Self> {
+        let mut filter = Self::new();
+        for node in tree {
+            if node.is_file() && node.name == CRUSTASYNC_IGNORE_FILE {
+                let content = fs.read(&node.path).await?;
+                filter.add(
+                    node.path.parent().unwrap(),
+                    &String::from_utf8_lossy(&content),
+                );
+            }
+        }
+        Ok(filter)
+    }
+
+    // Only the path itself is matched, callers don't descend into ignored directories
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for dir in path.ancestors().skip(1) {
+            let Some(gitignore) = self.rules.get(dir) else {
+                continue;
+            };
+            match gitignore.matched(path.strip_prefix(dir).unwrap(), is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+
+    // Copy of the tree without the ignored nodes, with directory hashes re-computed
+    pub fn prune(&self, tree: &Node) -> Node {
+        if tree.is_file() {
+            return tree.clone();
+        }
+        let children = tree
+            .children
+            .iter()
+            .filter(|child| !self.is_ignored(&child.path, child.is_dir()))
+            .map(|child| self.prune(child))
+            .collect();
+        Node::new_dir(
+            tree.name.clone(),
+            tree.path.clone(),
+            tree.updated_at,
+            children,
+        )
+    }
+}
+
+// endregion
+
 // ------------------------------
 // region FileSystem
 // ------------------------------
 
 pub const CRUSTASYNC_CONFIG_FILE: &str = ".crustasync";
 
+// Content of an opened file, read as a stream
+pub struct ReadStream {
+    pub reader: Box<dyn AsyncRead + Send + Unpin>,
+    // in bytes
+    pub size: u64,
+    // modification time to give the written file, the current time if None
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl ReadStream {
+    pub fn new(reader: impl AsyncRead + Send + Unpin + 'static, size: u64) -> Self {
+        Self {
+            reader: Box::new(reader),
+            size,
+            updated_at: None,
+        }
+    }
+}
+
+impl From<Vec<u8>> for ReadStream {
+    fn from(content: Vec<u8>) -> Self {
+        let size = content.len() as u64;
+        Self::new(Cursor::new(content), size)
+    }
+}
+
+// Same as try_join_all, but with at most limit futures polled at once
+pub async fn try_join_limited<T>(
+    futures: impl IntoIterator<Item = impl Future<Output = Result<T>>>,
+    limit: usize,
+) -> Result<Vec<T>> {
+    // collected first, Send can't be proven through a lazy iterator of closures
+    let futures = futures.into_iter().collect::<Vec<_>>();
+    stream::iter(futures)
+        .buffered(limit.max(1))
+        .try_collect()
+        .await
+}
+
+// Max number of requests a file system sends at once, e.g. to stay under rate limits.
+// Shared by every caller & every clone, so tree building & tasks running at once stay under it together
+#[derive(Debug, Clone)]
+pub struct RequestLimit(Arc<Semaphore>);
+
+impl RequestLimit {
+    pub fn new(limit: usize) -> Self {
+        Self(Arc::new(Semaphore::new(limit.max(1))))
+    }
+
+    // Hold the permit while sending a single request, never while acquiring another one
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        self.0
+            .acquire()
+            .await
+            .expect("the semaphore is never closed")
+    }
+}
+
 #[async_trait]
-pub trait FileSystem {
+pub trait FileSystem: Send + Sync {
     async fn write(&self, path: &Path, content: &[u8]) -> Result<()>;
 
     async fn read(&self, path: &Path) -> Result<Vec<u8>>;
@@ -106,6 +308,18 @@ pub trait FileSystem {
 
     async fn build_tree(&self) -> Result<Node>;
 
+    // Streaming versions of read & write, so that big files don't have to fit in memory.
+    // By default the whole file is buffered, file systems that can stream override them
+    async fn open_read(&self, path: &Path) -> Result<ReadStream> {
+        Ok(self.read(path).await?.into())
+    }
+
+    async fn write_stream(&self, path: &Path, mut stream: ReadStream) -> Result<()> {
+        let mut content = Vec::with_capacity(stream.size as usize);
+        stream.reader.read_to_end(&mut content).await?;
+        self.write(path, &content).await
+    }
+
     async fn get_tree(&self, force_sync: bool) -> Result<Node> {
         if force_sync {
             debug!("Force sync fs");
@@ -1,27 +1,39 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::fmt::Formatter;
+use std::io::ErrorKind;
 use std::iter::zip;
 use std::path::{Path, PathBuf, MAIN_SEPARATOR_STR};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use async_trait::async_trait;
-use chrono::{DateTime, Utc};
-use futures::future::join_all;
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, TimeDelta, Utc};
+use clap::ValueEnum;
+use futures::future::try_join_all;
 use itertools::Itertools;
-use log::{debug, info};
-use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
-use reqwest::Client as ReqwestClient;
-use serde::Deserialize;
+use log::{debug, info, warn};
+use rand::Rng;
+use reqwest::header::{
+    HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_RANGE, RANGE, RETRY_AFTER,
+};
+use reqwest::{Client as ReqwestClient, RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
 use tokio::sync::{Mutex, RwLock};
+use tokio_util::io::StreamReader;
 use url::Url;
 
 use crate::cli::CLIOption;
-use crate::crustasyncfs::base::{ContentHash, FileSystem, Node, NodeType, CRUSTASYNC_CONFIG_FILE};
+use crate::crustasyncfs::base::{
+    ContentHash, FileSystem, Node, NodeType, ReadStream, RequestLimit, CRUSTASYNC_CONFIG_FILE,
+};
 use crate::error::{Error, Result};
 use crate::oauth::AuthError;
 use crate::oauth::{AuthToken, OAuthPublicClient};
@@ -31,11 +43,39 @@ use crate::oauth::{AuthToken, OAuthPublicClient};
 // ------------------------------
 
 pub enum GDError {
-    MissingField { field: String },
-    InvalidData { field: String, message: String },
-    FileNotFound { file: String },
-    ParentNotFound { file: String },
+    MissingField {
+        field: String,
+    },
+    InvalidData {
+        field: String,
+        message: String,
+    },
+    FileNotFound {
+        file: String,
+    },
+    ParentNotFound {
+        file: String,
+    },
+    AmbiguousPath {
+        path: String,
+        ids: Vec<String>,
+    },
+    DuplicateName {
+        path: String,
+        ids: Vec<String>,
+    },
     Authentication(AuthError),
+    // rate limits, server errors & dropped connections, worth retrying
+    Transient {
+        status: Option<StatusCode>,
+        message: String,
+        retry_after: Option<Duration>,
+    },
+    // the request itself is wrong, retrying won't help
+    Permanent {
+        status: StatusCode,
+        message: String,
+    },
 }
 
 impl std::error::Error for GDError {}
@@ -59,7 +99,70 @@ impl std::fmt::Display for GDError {
             GDError::ParentNotFound { file } => {
                 write!(f, "GDError: Cannot find parent of {file}")
             }
+            GDError::AmbiguousPath { path, ids } => {
+                write!(
+                    f,
+                    "GDError: {} folders match {path}, use gd-id:<id> with one of {}",
+                    ids.len(),
+                    ids.join(", ")
+                )
+            }
+            GDError::DuplicateName { path, ids } => {
+                write!(
+                    f,
+                    "GDError: {} files are named {path} ({}), use --gd-duplicates newest or rename",
+                    ids.len(),
+                    ids.join(", ")
+                )
+            }
             GDError::Authentication(error) => std::fmt::Display::fmt(error, f),
+            GDError::Transient {
+                status: Some(status),
+                message,
+                ..
+            } => write!(f, "GDError: Transient error {status}, {message}"),
+            GDError::Transient { message, .. } => write!(f, "GDError: Transient error, {message}"),
+            GDError::Permanent { status, message } => {
+                write!(f, "GDError: Request failed {status}, {message}")
+            }
+        }
+    }
+}
+
+impl GDError {
+    async fn from_response(res: Response) -> Self {
+        let status = res.status();
+        let retry_after = res
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_retry_after);
+        let body = res.text().await.unwrap_or_default();
+
+        // https://developers.google.com/drive/api/guides/handle-errors
+        let (message, reasons) = match serde_json::from_str::<GDErrorResp>(&body) {
+            Ok(GDErrorResp { error }) => (
+                error.message,
+                error.errors.into_iter().map(|e| e.reason).collect(),
+            ),
+            Err(_) => (body, vec![]),
+        };
+        let rate_limited = reasons
+            .iter()
+            .any(|reason| reason == "userRateLimitExceeded" || reason == "rateLimitExceeded");
+
+        if status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT
+            || (status == StatusCode::FORBIDDEN && rate_limited)
+        {
+            GDError::Transient {
+                status: Some(status),
+                message,
+                retry_after,
+            }
+        } else {
+            GDError::Permanent { status, message }
         }
     }
 }
@@ -79,7 +182,7 @@ impl From<AuthError> for GDError {
 // https://developers.google.com/drive/api/guides/search-files
 // https://developers.google.com/drive/api/reference/rest/v3/files/list
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 struct GDFile {
     id: String,
     name: String,
@@ -89,13 +192,69 @@ struct GDFile {
     modified_time: DateTime<Utc>,
     #[serde(rename = "sha256Checksum")]
     sha256_checksum: Option<String>,
+    // int64 format is sent as string
+    size: Option<String>,
+    // increases on every change, int64 as string too
+    version: Option<String>,
+    #[serde(default)]
+    parents: Vec<String>,
+    #[serde(default)]
+    trashed: bool,
+    // only for files in shared drives
+    #[serde(rename = "driveId")]
+    drive_id: Option<String>,
 }
 
 impl GDFile {
+    fn size(&self) -> u64 {
+        self.size
+            .as_ref()
+            .and_then(|size| size.parse().ok())
+            .unwrap_or_default()
+    }
+
     fn is_dir(&self) -> bool {
         self.mime_type == GOOGLE_DRIVE_FOLDER_MIME_TYPE
     }
 
+    // Docs, Sheets, Slides... have no binary content nor checksum, they can only be exported
+    fn is_workspace_doc(&self) -> bool {
+        !self.is_dir()
+            && self
+                .mime_type
+                .starts_with(GOOGLE_WORKSPACE_MIME_TYPE_PREFIX)
+    }
+
+    // Mime type & file extension to export a workspace doc to, None if it cannot be exported
+    fn export_type(&self, format: ExportFormat) -> Option<(&'static str, &'static str)> {
+        let kind = self
+            .mime_type
+            .strip_prefix(GOOGLE_WORKSPACE_MIME_TYPE_PREFIX)?;
+        let export_type = match (kind, format) {
+            ("document", ExportFormat::Office) => (DOCX_MIME_TYPE, "docx"),
+            ("document", ExportFormat::OpenDocument) => (ODT_MIME_TYPE, "odt"),
+            ("spreadsheet", ExportFormat::Office) => (XLSX_MIME_TYPE, "xlsx"),
+            ("spreadsheet", ExportFormat::OpenDocument) => (ODS_MIME_TYPE, "ods"),
+            ("presentation", ExportFormat::Office) => (PPTX_MIME_TYPE, "pptx"),
+            ("presentation", ExportFormat::OpenDocument) => (ODP_MIME_TYPE, "odp"),
+            ("document" | "spreadsheet" | "presentation" | "drawing", _) => (PDF_MIME_TYPE, "pdf"),
+            _ => return None,
+        };
+        Some(export_type)
+    }
+
+    // Exports are generated on the fly, so their content can't be hashed without downloading them.
+    // Use the version & modified time instead, which change whenever the doc is edited
+    fn synthetic_hash(&self, extension: &str) -> ContentHash {
+        let mut hasher = Sha256::new();
+        hasher.update(b"gd-export");
+        hasher.update(&self.id);
+        hasher.update(self.version.as_deref().unwrap_or_default());
+        hasher.update(self.modified_time.to_rfc3339());
+        hasher.update(extension);
+        hasher.finalize().into()
+    }
+
     fn assert_is_dir(&self) -> Result<()> {
         if self.is_dir() {
             Ok(())
@@ -123,6 +282,37 @@ impl GDFile {
     }
 }
 
+// What to do with Google Workspace docs, which have no binary content
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum WorkspaceDocPolicy {
+    // leave them out of the tree
+    Skip,
+    // sync a copy exported in --gd-export-format, the docs themselves are never deleted or overwritten
+    Export,
+    // leave them out of the tree & never delete or overwrite them
+    Opaque,
+}
+
+// What to do when several files of a folder have the same name, which Google Drive allows
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum DuplicatePolicy {
+    // refuse to sync
+    Error,
+    // sync the most recently modified one & leave the others out of the tree
+    Newest,
+    // sync all of them, all but one under a `name (duplicate).ext` path
+    Rename,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum ExportFormat {
+    // docx, xlsx, pptx
+    Office,
+    // odt, ods, odp
+    OpenDocument,
+    Pdf,
+}
+
 #[derive(Debug, Deserialize)]
 struct GDResp {
     #[serde(rename = "nextPageToken")]
@@ -130,6 +320,138 @@ struct GDResp {
     files: Vec<GDFile>,
 }
 
+// https://developers.google.com/drive/api/reference/rest/v3/changes/list
+
+#[derive(Debug, Deserialize)]
+struct GDChangesResp {
+    #[serde(rename = "nextPageToken")]
+    next_page_token: Option<String>,
+    // only in the last page
+    #[serde(rename = "newStartPageToken")]
+    new_start_page_token: Option<String>,
+    changes: Vec<GDChange>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GDChange {
+    // absent for changes to shared drives themselves
+    #[serde(rename = "fileId")]
+    file_id: Option<String>,
+    #[serde(default)]
+    removed: bool,
+    file: Option<GDFile>,
+}
+
+// Every file & folder under the root dir, kept in the config dir between runs
+// so that later runs only fetch what changed since start_page_token
+#[derive(Debug, Serialize, Deserialize)]
+struct TreeCache {
+    root_id: String,
+    start_page_token: String,
+    // by id
+    files: HashMap<String, GDFile>,
+}
+
+// Hash of the last export of a doc, still valid while the doc & the export format are the same,
+// so that the doc matches its exported copy on the other side instead of always differing
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ExportedHash {
+    version: Option<String>,
+    modified_time: DateTime<Utc>,
+    extension: String,
+    content_hash: ContentHash,
+    size: u64,
+}
+
+impl TreeCache {
+    // Update the files from a page of changes, returns the ids of the folders it didn't have
+    fn apply_changes(&mut self, changes: Vec<GDChange>) -> Vec<String> {
+        let mut new_folders = vec![];
+        for change in changes {
+            let Some(file_id) = change.file_id else {
+                continue;
+            };
+            match change.file {
+                Some(file) if !change.removed && !file.trashed => {
+                    if file.is_dir() && !self.files.contains_key(&file_id) {
+                        new_folders.push(file_id.clone());
+                    }
+                    self.files.insert(file_id, file);
+                }
+                _ => {
+                    self.files.remove(&file_id);
+                }
+            }
+        }
+        new_folders
+    }
+}
+
+impl ExportedHash {
+    fn matches(&self, gd_file: &GDFile, extension: &str) -> bool {
+        self.version == gd_file.version
+            && self.modified_time == gd_file.modified_time
+            && self.extension == extension
+    }
+}
+
+// endregion
+
+// ------------------------------
+// region Request
+// ------------------------------
+
+#[derive(Debug, Deserialize)]
+struct GDErrorResp {
+    error: GDErrorDetail,
+}
+
+#[derive(Debug, Deserialize)]
+struct GDErrorDetail {
+    message: String,
+    #[serde(default)]
+    errors: Vec<GDErrorReason>,
+}
+
+#[derive(Debug, Deserialize)]
+struct GDErrorReason {
+    #[serde(default)]
+    reason: String,
+}
+
+// Exponential delay for the n-th retry, unless the server said how long to wait,
+// plus up to 1s of jitter so that parallel requests don't retry all at once
+fn backoff_delay(retries: u32, retry_after: Option<Duration>) -> Duration {
+    let delay = retry_after.unwrap_or_else(|| {
+        GOOGLE_DRIVE_BACKOFF_BASE
+            .saturating_mul(2u32.saturating_pow(retries.saturating_sub(1)))
+            .min(GOOGLE_DRIVE_BACKOFF_MAX)
+    });
+    delay + Duration::from_millis(rand::rng().random_range(0..1000))
+}
+
+// Retry-After is either a number of seconds or a HTTP date
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    (date.with_timezone(&Utc) - Utc::now()).to_std().ok()
+}
+
+// Connection errors & server side errors are worth retrying.
+// A body cut short is reported as a decode error too, caused by an io error unlike bad json
+fn is_retryable(error: &reqwest::Error) -> bool {
+    match error.status() {
+        Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+        None if error.is_decode() => {
+            std::iter::successors(std::error::Error::source(error), |e| e.source())
+                .any(|e| e.is::<std::io::Error>())
+        }
+        None => !error.is_builder(),
+    }
+}
+
 // endregion
 
 // ------------------------------
@@ -145,18 +467,63 @@ const GOOGLE_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
 const GOOGLE_DRIVE_API_URL: &str = "https://www.googleapis.com/drive/v3";
 const GOOGLE_DRIVE_UPLOAD_API_URL: &str = "https://www.googleapis.com/upload/drive/v3/files";
 const GOOGLE_DRIVE_LS_PAGE_SIZE: &str = "200";
+const GOOGLE_DRIVE_CHANGES_PAGE_SIZE: &str = "1000";
+const GD_FILE_FIELDS: &str =
+    "id, name, mimeType, modifiedTime, sha256Checksum, size, version, parents, trashed, driveId";
+// consecutive failures of a request, an upload chunk or a download before giving up
+const GOOGLE_DRIVE_MAX_RETRIES: u32 = 5;
+const GOOGLE_DRIVE_BACKOFF_BASE: Duration = Duration::from_secs(1);
+const GOOGLE_DRIVE_BACKOFF_MAX: Duration = Duration::from_secs(64);
+// refresh the access token when it's about to expire, so that it's still valid when used
+const TOKEN_REFRESH_MARGIN: TimeDelta = TimeDelta::minutes(5);
 
 const GOOGLE_DRIVE_FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
+const GOOGLE_WORKSPACE_MIME_TYPE_PREFIX: &str = "application/vnd.google-apps.";
+
+// https://developers.google.com/drive/api/guides/ref-export-formats
+const DOCX_MIME_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.wordprocessingml.document";
+const XLSX_MIME_TYPE: &str = "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet";
+const PPTX_MIME_TYPE: &str =
+    "application/vnd.openxmlformats-officedocument.presentationml.presentation";
+const ODT_MIME_TYPE: &str = "application/vnd.oasis.opendocument.text";
+const ODS_MIME_TYPE: &str = "application/vnd.oasis.opendocument.spreadsheet";
+const ODP_MIME_TYPE: &str = "application/vnd.oasis.opendocument.presentation";
+const PDF_MIME_TYPE: &str = "application/pdf";
 
 const CONFIG_FILE_NAME: &str = "google_drive.json";
+const CACHE_DIR_NAME: &str = "google_drive_cache";
 
 #[derive(Debug, Clone)]
 pub struct GoogleDriveFileSystem {
-    auth_token: AuthToken,
+    auth_token: Arc<RwLock<AuthToken>>,
+    // where the token is saved after being refreshed
+    token_file: PathBuf,
     http_client: ReqwestClient,
     root_dir: PathBuf,
     path_to_meta: Arc<RwLock<HashMap<PathBuf, GDFile>>>,
     initialized: Arc<Mutex<bool>>,
+    // in bytes, a multiple of 256 KiB as required by Google Drive
+    upload_chunk_size: u64,
+    docs_policy: WorkspaceDocPolicy,
+    export_format: ExportFormat,
+    duplicate_policy: DuplicatePolicy,
+    // skip the trash when removing files
+    permanent_delete: bool,
+    // directories containing opaque or exported workspace docs, at any depth
+    opaque_dirs: Arc<RwLock<HashSet<PathBuf>>>,
+    // ExportedHash by doc id, saved in exports_file
+    exported_hashes: Arc<RwLock<HashMap<String, ExportedHash>>>,
+    // id of the shared drive containing root_dir, None for My Drive
+    drive_id: Option<String>,
+    // set when the root is given by id instead of path
+    root_id: Option<String>,
+    // TreeCache of this root dir
+    cache_file: PathBuf,
+    exports_file: PathBuf,
+    // ignore the cache & list every folder
+    full_rehash: bool,
+    requests: RequestLimit,
 }
 
 impl GoogleDriveFileSystem {
@@ -188,13 +555,102 @@ impl GoogleDriveFileSystem {
 
         let http_client = reqwest::Client::new();
 
-        Ok(Self {
-            auth_token,
+        let root_dir_hash = Sha256::digest(root_dir.to_string_lossy().as_bytes());
+        let mut cache_file = opt.config_dir.join(CACHE_DIR_NAME);
+        cache_file.push(format!("{}.json", hex::encode(&root_dir_hash[..8])));
+        let exports_file = cache_file.with_extension("exports.json");
+
+        let mut fs = Self {
+            auth_token: Arc::new(RwLock::new(auth_token)),
+            token_file: gd_file,
             http_client,
             root_dir: root_dir.to_path_buf(),
             path_to_meta: Arc::new(RwLock::new(HashMap::default())),
             initialized: Arc::new(Mutex::new(false)),
-        })
+            upload_chunk_size: opt.gd_chunk_size * 1024 * 1024,
+            docs_policy: opt.gd_docs,
+            export_format: opt.gd_export_format,
+            duplicate_policy: opt.gd_duplicates,
+            permanent_delete: opt.gd_permanent_delete,
+            opaque_dirs: Arc::new(RwLock::new(HashSet::default())),
+            exported_hashes: Arc::new(RwLock::new(HashMap::default())),
+            drive_id: None,
+            root_id: None,
+            cache_file,
+            exports_file,
+            full_rehash: opt.full_rehash,
+            requests: RequestLimit::new(opt.gd_concurrency as usize),
+        };
+
+        if let Some(drive_name) = Self::shared_drive_name(root_dir) {
+            fs.drive_id = Some(fs.get_shared_drive_id(drive_name).await?);
+        }
+        Ok(fs)
+    }
+
+    // The root is the folder with this id, wherever it is, e.g. in a folder shared with us
+    pub async fn from_folder_id(opt: &CLIOption, folder_id: &str) -> Result<Self> {
+        let mut fs = Self::new(opt, Path::new(folder_id)).await?;
+        let root_meta = fs.metadata(folder_id).await?;
+        root_meta.assert_is_dir()?;
+        fs.root_id = Some(root_meta.id);
+        fs.drive_id = root_meta.drive_id;
+        Ok(fs)
+    }
+
+    // `@Name/path` is a path in the shared drive called Name
+    fn shared_drive_name(root_dir: &Path) -> Option<&str> {
+        let first = root_dir
+            .iter()
+            .find(|dir_name| *dir_name != OsStr::new(MAIN_SEPARATOR_STR))?;
+        first.to_str()?.strip_prefix('@')
+    }
+
+    async fn get_shared_drive_id(&self, drive_name: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Drive {
+            id: String,
+        }
+        #[derive(Deserialize)]
+        struct DrivesResp {
+            drives: Vec<Drive>,
+        }
+
+        let query = [
+            (
+                "q",
+                format!("name = '{}'", Self::escape_gd_query(drive_name)),
+            ),
+            ("fields", "drives(id)".to_string()),
+        ];
+        let req_builder = self
+            .http_client
+            .get(format!("{GOOGLE_DRIVE_API_URL}/drives"))
+            .query(&query);
+        let res: DrivesResp = self.send(req_builder).await?.json().await?;
+
+        match res.drives.as_slice() {
+            [drive] => {
+                debug!("Shared drive {drive_name} has id {}", drive.id);
+                Ok(drive.id.clone())
+            }
+            [] => Err(Error::from(GDError::FileNotFound {
+                file: format!("@{drive_name}"),
+            })),
+            drives => Err(Error::from(GDError::AmbiguousPath {
+                path: format!("@{drive_name}"),
+                ids: drives.iter().map(|drive| drive.id.clone()).collect(),
+            })),
+        }
+    }
+
+    // Query parameters to list files & changes in the shared drive, if any
+    fn drive_query(&self) -> Vec<(&'static str, String)> {
+        let mut query = vec![("includeItemsFromAllDrives", "true".to_string())];
+        if let Some(drive_id) = &self.drive_id {
+            query.push(("driveId", drive_id.clone()));
+        }
+        query
     }
 
     fn auth_client() -> Result<OAuthPublicClient> {
@@ -222,142 +678,335 @@ impl GoogleDriveFileSystem {
             .map_err(|e| GDError::from(e).into())
     }
 
-    async fn auth_header(&self) -> Result<HeaderMap> {
-        let mut headers = HeaderMap::new();
-        let bearer = format!("Bearer {}", self.auth_token.access_token);
-        let header_value = HeaderValue::from_str(&bearer).map_err(|e| GDError::InvalidData {
-            field: "Authorizaion header".to_string(),
-            message: e.to_string(),
-        })?;
-        headers.insert(AUTHORIZATION, header_value);
-        Ok(headers)
+    // Access token for the next request, refreshed a bit before it expires
+    async fn access_token(&self) -> Result<String> {
+        let token = self.auth_token.read().await;
+        if token.expires_at - TOKEN_REFRESH_MARGIN > Utc::now() {
+            return Ok(token.access_token.clone());
+        }
+        let stale_access_token = token.access_token.clone();
+        drop(token);
+        self.refresh_token(&stale_access_token).await
     }
 
-    async fn build_node(&self, node_id: &str, parent_path: &Path, is_root: bool) -> Result<Node> {
-        let meta = self.metadata(node_id).await?;
+    // Refresh the token, unless a concurrent request already replaced the stale one
+    async fn refresh_token(&self, stale_access_token: &str) -> Result<String> {
+        let mut token = self.auth_token.write().await;
+        if token.access_token == stale_access_token {
+            info!("Refreshing Google Drive access token");
+            *token = Self::auth_client()?
+                .refresh_token(&mut token)
+                .await
+                .map_err(|e| Error::from(GDError::from(e)))?;
+            Self::save_token(&token, &self.token_file).await?;
+        }
+        Ok(token.access_token.clone())
+    }
 
-        let path = if is_root {
-            PathBuf::from("")
-        } else {
-            parent_path.to_path_buf().join(&meta.name)
-        };
+    // Send a Drive API request with the current access token.
+    // Transient errors are retried with jittered exponential backoff,
+    // a rejected token is refreshed once. Non-success responses are turned into errors
+    async fn send(&self, req_builder: RequestBuilder) -> Result<Response> {
+        let mut retries = 0;
+        let mut refreshed = false;
+        loop {
+            let access_token = self.access_token().await?;
+            let Some(req) = req_builder.try_clone() else {
+                return Err(Error::Unknown(anyhow!("Cannot retry a streaming request")));
+            };
+            let mut req = req.build()?;
+            // without it, files in shared drives are not found
+            req.url_mut()
+                .query_pairs_mut()
+                .append_pair("supportsAllDrives", "true");
+            let bearer = HeaderValue::from_str(&format!("Bearer {access_token}")).map_err(|e| {
+                GDError::InvalidData {
+                    field: "Authorization header".to_string(),
+                    message: e.to_string(),
+                }
+            })?;
+            req.headers_mut().insert(AUTHORIZATION, bearer);
 
-        let mut path_to_meta = self.path_to_meta.write().await;
-        path_to_meta.insert(path.clone(), meta.clone());
-        drop(path_to_meta);
+            let res = {
+                let _permit = self.requests.acquire().await;
+                self.http_client.execute(req).await
+            };
+            let error = match res {
+                Ok(res) if res.status() == StatusCode::UNAUTHORIZED && !refreshed => {
+                    debug!("Access token rejected, refreshing it");
+                    refreshed = true;
+                    self.refresh_token(&access_token).await?;
+                    continue;
+                }
+                Ok(res) if res.status().is_client_error() || res.status().is_server_error() => {
+                    GDError::from_response(res).await
+                }
+                Ok(res) => return Ok(res),
+                Err(e) if is_retryable(&e) => GDError::Transient {
+                    status: None,
+                    message: e.to_string(),
+                    retry_after: None,
+                },
+                Err(e) => return Err(e.into()),
+            };
 
-        // handle directory
-        if meta.is_dir() {
-            let children = self.ls(node_id).await?;
-
-            let futures: Vec<_> = children
-                .into_iter()
-                .map(|gd_file| async {
-                    if gd_file.is_dir() {
-                        Box::pin(self.build_node(&gd_file.id, &path, false)).await
-                    } else {
-                        let child_path = path.join(&gd_file.name);
-                        let content_hash = gd_file.content_hash()?;
-                        let node = Node {
-                            node_type: NodeType::File,
-                            name: gd_file.name.clone(),
-                            path: child_path.clone(),
-                            updated_at: gd_file.modified_time,
-                            content_hash,
-                            children: vec![],
-                        };
-                        let mut path_to_meta = self.path_to_meta.write().await;
-                        path_to_meta.insert(child_path, gd_file);
-                        drop(path_to_meta);
-                        Ok(node)
-                    }
-                })
-                .collect();
-
-            let mut children = vec![];
-            for res in join_all(futures).await {
-                match res {
-                    Ok(node) => {
-                        if !(is_root && node.name == CRUSTASYNC_CONFIG_FILE) {
-                            // still update .crustasync config id, path, but do not include in tree
-                            children.push(node)
-                        }
-                    }
-                    Err(e) => return Err(e),
+            match error {
+                GDError::Transient { retry_after, .. } if retries < GOOGLE_DRIVE_MAX_RETRIES => {
+                    retries += 1;
+                    let delay = backoff_delay(retries, retry_after);
+                    warn!("{error}. Retrying in {delay:?} ({retries}/{GOOGLE_DRIVE_MAX_RETRIES})");
+                    tokio::time::sleep(delay).await;
                 }
+                error => return Err(error.into()),
             }
+        }
+    }
 
-            let mut hasher = Sha256::new();
+    async fn load_cache(&self, root_id: &str) -> Option<TreeCache> {
+        if self.full_rehash {
+            return None;
+        }
+        let content = fs::read(&self.cache_file).await.ok()?;
+        let cache: TreeCache = serde_json::from_slice(&content).ok()?;
+        (cache.root_id == root_id).then_some(cache)
+    }
 
-            children.sort_by_key(|node| node.name.clone().to_lowercase());
+    async fn save_cache(&self, cache: &TreeCache) -> Result<()> {
+        debug!("Saving tree cache to {:?}", self.cache_file);
+        fs::create_dir_all(self.cache_file.parent().unwrap()).await?;
+        fs::write(&self.cache_file, serde_json::to_vec(cache)?).await?;
+        Ok(())
+    }
 
-            children.iter().for_each(|node| {
-                let filename = node.name.as_bytes();
-                hasher.update(filename);
-                hasher.update(node.content_hash);
-            });
+    async fn load_exported_hashes(&self) -> HashMap<String, ExportedHash> {
+        if self.full_rehash {
+            return HashMap::new();
+        }
+        let Ok(content) = fs::read(&self.exports_file).await else {
+            return HashMap::new();
+        };
+        serde_json::from_slice(&content).unwrap_or_default()
+    }
 
-            let node = Node {
-                node_type: NodeType::Directory,
-                name: meta.name,
-                path,
-                updated_at: meta.modified_time,
-                content_hash: hasher.finalize().into(),
-                children,
-            };
-            return Ok(node);
+    // Remember the hash of an export, failing to do so only means exporting the doc again
+    async fn save_exported_hash(&self, gd_file: &GDFile, content: &[u8]) {
+        let Some((_, extension)) = gd_file.export_type(self.export_format) else {
+            return;
+        };
+        let mut exported_hashes = self.exported_hashes.write().await;
+        exported_hashes.insert(
+            gd_file.id.clone(),
+            ExportedHash {
+                version: gd_file.version.clone(),
+                modified_time: gd_file.modified_time,
+                extension: extension.to_string(),
+                content_hash: Sha256::digest(content).into(),
+                size: content.len() as u64,
+            },
+        );
+        let res = async {
+            fs::create_dir_all(self.exports_file.parent().unwrap()).await?;
+            fs::write(&self.exports_file, serde_json::to_vec(&*exported_hashes)?).await?;
+            Ok::<_, Error>(())
+        }
+        .await;
+        if let Err(e) = res {
+            warn!("Cannot save the hash of exported {}: {e}", gd_file.name);
         }
+    }
 
-        // handle file
-        let content_hash = meta.content_hash()?;
-        let node = Node {
-            node_type: NodeType::File,
-            name: meta.name,
-            path: path.clone(),
-            updated_at: meta.modified_time,
-            content_hash,
-            children: vec![],
+    async fn start_page_token(&self) -> Result<String> {
+        #[derive(Deserialize)]
+        struct StartPageTokenResp {
+            #[serde(rename = "startPageToken")]
+            start_page_token: String,
+        }
+
+        let req_builder = self
+            .http_client
+            .get(format!("{GOOGLE_DRIVE_API_URL}/changes/startPageToken"))
+            .query(&self.drive_query());
+        let res: StartPageTokenResp = self.send(req_builder).await?.json().await?;
+        Ok(res.start_page_token)
+    }
+
+    // Apply the changes made since the cache was saved
+    async fn update_cache(&self, cache: &mut TreeCache) -> Result<()> {
+        let mut page_token = cache.start_page_token.clone();
+        let mut new_folders = vec![];
+        loop {
+            debug!("Listing changes from page token {page_token}");
+            let query = [
+                ("pageToken", page_token.clone()),
+                ("pageSize", GOOGLE_DRIVE_CHANGES_PAGE_SIZE.to_string()),
+                ("includeRemoved", "true".to_string()),
+                (
+                    "fields",
+                    format!("nextPageToken, newStartPageToken, changes(fileId, removed, file({GD_FILE_FIELDS}))"),
+                ),
+            ];
+            let req_builder = self
+                .http_client
+                .get(format!("{GOOGLE_DRIVE_API_URL}/changes"))
+                .query(&query)
+                .query(&self.drive_query());
+            let res: GDChangesResp = self.send(req_builder).await?.json().await?;
+            debug!("Found {} changes", res.changes.len());
+            new_folders.extend(cache.apply_changes(res.changes));
+
+            match (res.next_page_token, res.new_start_page_token) {
+                (Some(next_page_token), _) => page_token = next_page_token,
+                (None, Some(new_start_page_token)) => {
+                    cache.start_page_token = new_start_page_token;
+                    return self.list_new_folders(new_folders, cache).await;
+                }
+                (None, None) => {
+                    return Err(Error::from(GDError::MissingField {
+                        field: "newStartPageToken".to_string(),
+                    }))
+                }
+            }
+        }
+    }
+
+    // Folders moved in from outside of the root come without their content, list it.
+    // Folders we don't know anything about yet are listed too, since we can't tell them apart
+    async fn list_new_folders(&self, folder_ids: Vec<String>, cache: &mut TreeCache) -> Result<()> {
+        for folder_id in folder_ids {
+            let is_under_root = cache
+                .files
+                .get(&folder_id)
+                .is_some_and(|folder| Self::is_under(folder, &cache.root_id, &cache.files));
+            if is_under_root {
+                self.list_subtree(&folder_id, &mut cache.files).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_under(file: &GDFile, root_id: &str, files: &HashMap<String, GDFile>) -> bool {
+        let mut visited = HashSet::new();
+        let mut parents = file.parents.clone();
+        while let Some(parent_id) = parents.pop() {
+            if parent_id == root_id {
+                return true;
+            }
+            if !visited.insert(parent_id.clone()) {
+                continue;
+            }
+            if let Some(parent) = files.get(&parent_id) {
+                parents.extend(parent.parents.iter().cloned());
+            }
+        }
+        false
+    }
+
+    // Add every file & folder under folder_id to files, listing folders level by level
+    async fn list_subtree(
+        &self,
+        folder_id: &str,
+        files: &mut HashMap<String, GDFile>,
+    ) -> Result<()> {
+        let mut folder_ids = vec![folder_id.to_string()];
+        while !folder_ids.is_empty() {
+            let listings = try_join_all(folder_ids.iter().map(|id| self.ls(id))).await?;
+            folder_ids = vec![];
+            for gd_file in listings.into_iter().flatten() {
+                if gd_file.is_dir() {
+                    folder_ids.push(gd_file.id.clone());
+                }
+                files.insert(gd_file.id.clone(), gd_file);
+            }
+        }
+        Ok(())
+    }
+
+    async fn export(&self, gd_file: &GDFile) -> Result<Vec<u8>> {
+        let Some((mime_type, _)) = gd_file.export_type(self.export_format) else {
+            return Err(Error::from(GDError::InvalidData {
+                field: "mimeType".to_string(),
+                message: format!(
+                    "Cannot export {} as {:?}",
+                    gd_file.mime_type, self.export_format
+                ),
+            }));
         };
-        Ok(node)
+        debug!("Exporting {} as {mime_type}", gd_file.name);
+
+        let req_builder = self
+            .http_client
+            .get(format!(
+                "{GOOGLE_DRIVE_API_URL}/files/{}/export",
+                gd_file.id
+            ))
+            .query(&[("mimeType", mime_type)]);
+        let content = self.send(req_builder).await?.bytes().await?;
+        Ok(content.into())
+    }
+
+    fn download_stream(&self, file_meta: &GDFile) -> ReadStream {
+        let download = Download {
+            fs: self.clone(),
+            url: format!("{}/files/{}", GOOGLE_DRIVE_API_URL, file_meta.id),
+            name: file_meta.name.clone(),
+            size: file_meta.size(),
+            expected_hash: file_meta.content_hash().ok(),
+            offset: 0,
+            hasher: Sha256::new(),
+            response: None,
+            retries: 0,
+        };
+
+        let size = download.size;
+        let stream = futures::stream::try_unfold(download, |mut download| async move {
+            let chunk = download.next_chunk().await?;
+            Ok::<_, std::io::Error>(chunk.map(|chunk| (chunk, download)))
+        });
+        ReadStream::new(StreamReader::new(Box::pin(stream)), size)
+    }
+
+    // Move to the trash, so that a bad sync can be undone, unless deleting permanently
+    async fn delete_by_id(&self, id: &str) -> Result<()> {
+        let url = format!("{GOOGLE_DRIVE_API_URL}/files/{id}");
+        let req_builder = if self.permanent_delete {
+            self.http_client.delete(url)
+        } else {
+            self.http_client
+                .patch(url)
+                .query(&[("fields", "id")])
+                .json(&json!({ "trashed": true }))
+        };
+        self.send(req_builder).await?;
+        Ok(())
     }
 
     async fn metadata(&self, file_id: &str) -> Result<GDFile> {
-        let headers = self.auth_header().await?;
-        let query = [("fields", "id, name, mimeType, modifiedTime")];
-        Ok(self
+        let query = [("fields", GD_FILE_FIELDS)];
+        let req_builder = self
             .http_client
             .get(format!("{GOOGLE_DRIVE_API_URL}/files/{file_id}"))
-            .headers(headers.clone())
-            .query(&query)
-            .send()
-            .await?
-            .json()
-            .await?)
+            .query(&query);
+        Ok(self.send(req_builder).await?.json().await?)
     }
 
     async fn ls(&self, directory_id: &str) -> Result<Vec<GDFile>> {
         debug!("Listing files drives in {directory_id}");
 
-        let headers = self.auth_header().await?;
         let mut query = vec![
             ("orderBy", "name".to_string()),
             ("pageSize", GOOGLE_DRIVE_LS_PAGE_SIZE.to_string()),
             ("q", Self::gd_query(directory_id, None::<&str>)),
-            (
-                "fields",
-                "nextPageToken, files(id, name, mimeType, modifiedTime, sha256Checksum)"
-                    .to_string(),
-            ),
+            ("fields", format!("nextPageToken, files({GD_FILE_FIELDS})")),
         ];
 
-        let mut res = self.do_ls_req(&headers, &query).await?;
+        let mut res = self.do_ls_req(&query).await?;
         let mut files = res.files;
         debug!("Found {} files in {}", files.len(), directory_id);
 
         while let Some(next_page_token) = res.next_page_token {
             debug!("Next page token in {}: {}", directory_id, next_page_token);
             query.push(("pageToken", next_page_token.clone()));
-            res = self.do_ls_req(&headers, &query).await?;
+            res = self.do_ls_req(&query).await?;
             debug!("Found {} files in {}", res.files.len(), directory_id);
             files.extend(res.files);
             query.pop();
@@ -367,14 +1016,16 @@ impl GoogleDriveFileSystem {
         Ok(files)
     }
 
-    async fn do_ls_req(&self, headers: &HeaderMap, query: &[(&str, String)]) -> Result<GDResp> {
-        let res = self
+    async fn do_ls_req(&self, query: &[(&str, String)]) -> Result<GDResp> {
+        let mut req_builder = self
             .http_client
             .get(format!("{GOOGLE_DRIVE_API_URL}/files"))
-            .headers(headers.clone())
             .query(&query)
-            .send()
-            .await?;
+            .query(&self.drive_query());
+        if self.drive_id.is_some() {
+            req_builder = req_builder.query(&[("corpora", "drive")]);
+        }
+        let res = self.send(req_builder).await?;
         debug!("Got response status: {}", res.status());
 
         Ok(res.json().await?)
@@ -386,6 +1037,7 @@ impl GoogleDriveFileSystem {
         let escaped_pid = Self::escape_gd_query(parent_id);
         let pid_query = format!("'{}' in parents", escaped_pid);
         query_parts.push(pid_query);
+        query_parts.push("trashed = false".to_string());
 
         if let Some(file_name) = file_name {
             let escaped_file_name = Self::escape_gd_query(file_name);
@@ -402,41 +1054,185 @@ impl GoogleDriveFileSystem {
     }
 
     async fn get_root_dir_id(&self) -> Result<String> {
+        if let Some(root_id) = &self.root_id {
+            return Ok(root_id.clone());
+        }
+
         let root_dir = OsStr::new(MAIN_SEPARATOR_STR);
-        let mut parent_dir_id = "root".to_string();
-        for dir_name in self.root_dir.iter() {
-            if dir_name != root_dir {
-                parent_dir_id = self
-                    .get_child_dir_id(&parent_dir_id, dir_name.to_str().unwrap())
-                    .await?;
-            };
+        // the root folder of a shared drive has the same id as the drive
+        let (mut parent_dir_id, skip) = match &self.drive_id {
+            Some(drive_id) => (drive_id.clone(), 1),
+            None => ("root".to_string(), 0),
+        };
+        let mut path = PathBuf::new();
+        for dir_name in self.root_dir.iter().filter(|d| *d != root_dir).skip(skip) {
+            path.push(dir_name);
+            parent_dir_id = self
+                .get_child_dir_id(&parent_dir_id, dir_name.to_str().unwrap(), &path)
+                .await?;
         }
 
         Ok(parent_dir_id)
     }
 
-    async fn get_child_dir_id(&self, parent_dir_id: &str, child_name: &str) -> Result<String> {
-        let headers = self.auth_header().await?;
+    // path is only used in error messages
+    async fn get_child_dir_id(
+        &self,
+        parent_dir_id: &str,
+        child_name: &str,
+        path: &Path,
+    ) -> Result<String> {
         let query = vec![
             ("q", Self::gd_query(parent_dir_id, Some(child_name))),
-            (
-                "fields",
-                "nextPageToken, files(id, name, mimeType, modifiedTime, sha256Checksum)"
-                    .to_string(),
-            ),
+            ("fields", format!("nextPageToken, files({GD_FILE_FIELDS})")),
         ];
 
-        let res = self.do_ls_req(&headers, &query).await?;
+        let res = self.do_ls_req(&query).await?;
 
-        if let Some(file) = res.files.first() {
-            Ok(file.id.clone())
-        } else {
-            Err(Error::from(GDError::FileNotFound {
-                file: child_name.to_string(),
-            }))
+        // names are not unique in Google Drive, picking one could sync the wrong folder
+        let dirs = res
+            .files
+            .into_iter()
+            .filter(GDFile::is_dir)
+            .collect::<Vec<_>>();
+        match dirs.as_slice() {
+            [dir] => Ok(dir.id.clone()),
+            [] => Err(Error::from(GDError::FileNotFound {
+                file: path.display().to_string(),
+            })),
+            dirs => Err(Error::from(GDError::AmbiguousPath {
+                path: path.display().to_string(),
+                ids: dirs.iter().map(|dir| dir.id.clone()).collect(),
+            })),
+        }
+    }
+
+    // Upload the stream to a resumable upload session, one chunk at a time.
+    // If a chunk fails, ask the session how many bytes it has received & continue from there
+    async fn upload_chunks(
+        &self,
+        session_url: &str,
+        mut stream: ReadStream,
+    ) -> Result<serde_json::Value> {
+        let total = stream.size;
+        // bytes from chunk_start that are not acknowledged by the session yet
+        let mut chunk = Vec::new();
+        let mut chunk_start = 0;
+        let mut retries = 0;
+        let mut refreshed = false;
+
+        loop {
+            let access_token = self.access_token().await?;
+            let missing = self.upload_chunk_size - chunk.len() as u64;
+            (&mut stream.reader)
+                .take(missing)
+                .read_to_end(&mut chunk)
+                .await?;
+            let chunk_end = chunk_start + chunk.len() as u64;
+            let is_last = chunk_end >= total;
+            if chunk_end > total || (!is_last && (chunk.len() as u64) < self.upload_chunk_size) {
+                return Err(Error::from(GDError::InvalidData {
+                    field: "upload stream".to_string(),
+                    message: format!("expect {total} bytes, got {chunk_end}"),
+                }));
+            }
+
+            let status = match self
+                .put_chunk(session_url, &access_token, &chunk, chunk_start, total)
+                .await
+            {
+                Ok(status) => {
+                    retries = 0;
+                    refreshed = false;
+                    status
+                }
+                // nothing is stored when the token is rejected, the chunk is sent again
+                Err(e) if e.status() == Some(StatusCode::UNAUTHORIZED) && !refreshed => {
+                    debug!("Access token rejected, refreshing it");
+                    refreshed = true;
+                    self.refresh_token(&access_token).await?;
+                    continue;
+                }
+                Err(e) if retries < GOOGLE_DRIVE_MAX_RETRIES && is_retryable(&e) => {
+                    retries += 1;
+                    warn!("Upload interrupted at byte {chunk_start}: {e}. Resuming ({retries})");
+                    tokio::time::sleep(backoff_delay(retries, None)).await;
+                    match self
+                        .query_upload_status(session_url, &access_token, total)
+                        .await
+                    {
+                        Ok(status) => status,
+                        Err(e) if is_retryable(&e) => continue,
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            match status {
+                UploadStatus::Done(file_meta) => return Ok(file_meta),
+                UploadStatus::Received(received) => {
+                    if received < chunk_start || received > chunk_end {
+                        return Err(Error::from(GDError::InvalidData {
+                            field: "upload Range header".to_string(),
+                            message: format!(
+                                "received {received} bytes, expect {chunk_start} to {chunk_end}"
+                            ),
+                        }));
+                    }
+                    debug!("Uploaded {received}/{total} bytes");
+                    chunk.drain(..(received - chunk_start) as usize);
+                    chunk_start = received;
+                }
+            }
         }
     }
 
+    async fn put_chunk(
+        &self,
+        session_url: &str,
+        access_token: &str,
+        chunk: &[u8],
+        chunk_start: u64,
+        total: u64,
+    ) -> reqwest::Result<UploadStatus> {
+        let content_range = if chunk.is_empty() {
+            format!("bytes */{total}")
+        } else {
+            let chunk_end = chunk_start + chunk.len() as u64 - 1;
+            format!("bytes {chunk_start}-{chunk_end}/{total}")
+        };
+        let _permit = self.requests.acquire().await;
+        let res = self
+            .http_client
+            .put(session_url)
+            .bearer_auth(access_token)
+            .header(CONTENT_RANGE, content_range)
+            .header(CONTENT_LENGTH, chunk.len())
+            .body(chunk.to_vec())
+            .send()
+            .await?;
+        UploadStatus::from_response(res).await
+    }
+
+    async fn query_upload_status(
+        &self,
+        session_url: &str,
+        access_token: &str,
+        total: u64,
+    ) -> reqwest::Result<UploadStatus> {
+        let _permit = self.requests.acquire().await;
+        let res = self
+            .http_client
+            .put(session_url)
+            .bearer_auth(access_token)
+            .header(CONTENT_RANGE, format!("bytes */{total}"))
+            .header(CONTENT_LENGTH, 0)
+            .send()
+            .await?;
+        UploadStatus::from_response(res).await
+    }
+
     pub async fn init(&self) -> Result<()> {
         let mut init = self.initialized.lock().await;
         if !(*init) {
@@ -448,9 +1244,409 @@ impl GoogleDriveFileSystem {
     }
 }
 
+enum UploadStatus {
+    // number of bytes the upload session has received so far
+    Received(u64),
+    // upload completed, with the file metadata
+    Done(serde_json::Value),
+}
+
+impl UploadStatus {
+    async fn from_response(res: Response) -> reqwest::Result<Self> {
+        if res.status().as_u16() != 308 {
+            return res.error_for_status()?.json().await.map(UploadStatus::Done);
+        }
+        // `Range: bytes=0-<last byte received>`, absent when nothing was received
+        let received = res
+            .headers()
+            .get(RANGE)
+            .and_then(|range| range.to_str().ok())
+            .and_then(|range| range.rsplit_once('-'))
+            .and_then(|(_, last)| last.parse::<u64>().ok())
+            .map_or(0, |last| last + 1);
+        Ok(UploadStatus::Received(received))
+    }
+}
+
+// A file being downloaded. If the connection drops,
+// the download resumes from the last byte received with a Range request
+struct Download {
+    fs: GoogleDriveFileSystem,
+    url: String,
+    name: String,
+    size: u64,
+    // checked once the whole file is received
+    expected_hash: Option<ContentHash>,
+    // number of bytes received so far
+    offset: u64,
+    hasher: Sha256,
+    response: Option<Response>,
+    retries: u32,
+}
+
+impl Download {
+    async fn request(&self) -> Result<Response> {
+        let query = (("alt", "media"), ("acknowledgeAbuse", "true"));
+        let mut req_builder = self.fs.http_client.get(&self.url).query(&query);
+        if self.offset > 0 {
+            req_builder = req_builder.header(RANGE, format!("bytes={}-", self.offset));
+        }
+        self.fs.send(req_builder).await
+    }
+
+    async fn next_chunk(&mut self) -> std::io::Result<Option<Bytes>> {
+        loop {
+            let error = match &mut self.response {
+                None => match self.request().await {
+                    // a server ignoring the Range header would send the file from the start
+                    Ok(res) if self.offset > 0 && res.status() != StatusCode::PARTIAL_CONTENT => {
+                        return Err(std::io::Error::other(format!(
+                            "Cannot resume download of {}, Range request not supported",
+                            self.name
+                        )));
+                    }
+                    Ok(res) => {
+                        self.response = Some(res);
+                        continue;
+                    }
+                    // already retried
+                    Err(e) => return Err(std::io::Error::other(e)),
+                },
+                Some(res) => match res.chunk().await {
+                    Ok(Some(chunk)) => {
+                        self.offset += chunk.len() as u64;
+                        self.hasher.update(&chunk);
+                        self.retries = 0;
+                        return Ok(Some(chunk));
+                    }
+                    Ok(None) if self.offset < self.size => {
+                        self.response = None;
+                        if self.retries >= GOOGLE_DRIVE_MAX_RETRIES {
+                            return Err(std::io::Error::from(ErrorKind::UnexpectedEof));
+                        }
+                        self.retries += 1;
+                        warn!(
+                            "Download of {} ended early at byte {}. Resuming ({})",
+                            self.name, self.offset, self.retries
+                        );
+                        continue;
+                    }
+                    Ok(None) => return self.verify().map(|_| None),
+                    Err(e) => e,
+                },
+            };
+
+            self.response = None;
+            if self.retries >= GOOGLE_DRIVE_MAX_RETRIES || !is_retryable(&error) {
+                return Err(std::io::Error::other(error));
+            }
+            self.retries += 1;
+            warn!(
+                "Download of {} interrupted at byte {}: {error}. Resuming ({})",
+                self.name, self.offset, self.retries
+            );
+            tokio::time::sleep(backoff_delay(self.retries, None)).await;
+        }
+    }
+
+    fn verify(&mut self) -> std::io::Result<()> {
+        let content_hash: ContentHash = std::mem::take(&mut self.hasher).finalize().into();
+        match self.expected_hash {
+            Some(expected_hash) if expected_hash != content_hash => Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Checksum mismatch for {}, expect {} got {}",
+                    self.name,
+                    hex::encode(expected_hash),
+                    hex::encode(content_hash)
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Builds the Node tree from the listing of every file under the root,
+// along with the tables GoogleDriveFileSystem needs to find files by path
+struct TreeBuilder<'a> {
+    fs: &'a GoogleDriveFileSystem,
+    // sorted by name
+    children: HashMap<&'a str, Vec<&'a GDFile>>,
+    exported_hashes: &'a HashMap<String, ExportedHash>,
+    path_to_meta: HashMap<PathBuf, GDFile>,
+    opaque_dirs: HashSet<PathBuf>,
+    // ids of the files in the tree
+    visited: HashSet<String>,
+}
+
+impl<'a> TreeBuilder<'a> {
+    fn new(
+        fs: &'a GoogleDriveFileSystem,
+        files: &'a HashMap<String, GDFile>,
+        exported_hashes: &'a HashMap<String, ExportedHash>,
+    ) -> Self {
+        let mut children: HashMap<&str, Vec<&GDFile>> = HashMap::new();
+        for file in files.values() {
+            for parent_id in &file.parents {
+                children.entry(parent_id).or_default().push(file);
+            }
+        }
+        children
+            .values_mut()
+            .for_each(|files| files.sort_by(|a, b| a.name.cmp(&b.name)));
+
+        Self {
+            fs,
+            children,
+            exported_hashes,
+            path_to_meta: HashMap::new(),
+            opaque_dirs: HashSet::new(),
+            visited: HashSet::new(),
+        }
+    }
+
+    fn build_node(&mut self, meta: &GDFile, path: PathBuf) -> Result<Node> {
+        self.visited.insert(meta.id.clone());
+        self.path_to_meta.insert(path.clone(), meta.clone());
+        let is_root = path.as_os_str().is_empty();
+        let mut children = vec![];
+        let files = self
+            .children
+            .get(meta.id.as_str())
+            .cloned()
+            .unwrap_or_default();
+        for (gd_file, name) in self.resolve_duplicates(files, &path)? {
+            let child_path = path.join(&name);
+            if gd_file.is_dir() {
+                // a folder can't contain itself, unless parents were edited concurrently
+                if !self.visited.contains(&gd_file.id) {
+                    children.push(self.build_node(gd_file, child_path)?);
+                }
+            } else if gd_file.is_workspace_doc() {
+                self.visited.insert(gd_file.id.clone());
+                if let Some(node) = self.workspace_doc_node(gd_file, &name, &path) {
+                    children.push(node);
+                }
+            } else {
+                self.visited.insert(gd_file.id.clone());
+                self.path_to_meta
+                    .insert(child_path.clone(), gd_file.clone());
+                // still update .crustasync config id, path, but do not include in tree
+                if is_root && gd_file.name == CRUSTASYNC_CONFIG_FILE {
+                    continue;
+                }
+                children.push(Node {
+                    node_type: NodeType::File,
+                    name,
+                    path: child_path,
+                    updated_at: gd_file.modified_time,
+                    content_hash: gd_file.content_hash()?,
+                    children: vec![],
+                    size: gd_file.size(),
+                    inode: None,
+                });
+            }
+        }
+
+        Ok(Node::new_dir(
+            meta.name.clone(),
+            path,
+            meta.modified_time,
+            children,
+        ))
+    }
+
+    // Name in the tree: exported docs are named after their export, e.g. `Report.docx`
+    fn tree_name(&self, gd_file: &GDFile) -> String {
+        match gd_file.export_type(self.fs.export_format) {
+            Some((_, extension))
+                if gd_file.is_workspace_doc()
+                    && self.fs.docs_policy == WorkspaceDocPolicy::Export =>
+            {
+                format!("{}.{extension}", gd_file.name)
+            }
+            _ => gd_file.name.clone(),
+        }
+    }
+
+    // Pair each file of a folder with the name it has in the tree, files sorted by name
+    // Files left out are still visited, so that the cache keeps them
+    fn resolve_duplicates(
+        &mut self,
+        files: Vec<&'a GDFile>,
+        parent_path: &Path,
+    ) -> Result<Vec<(&'a GDFile, String)>> {
+        // docs that are not exported don't take a path in the tree
+        let (files, skipped_docs): (Vec<_>, Vec<_>) = files.into_iter().partition(|f| {
+            !f.is_workspace_doc()
+                || (self.fs.docs_policy == WorkspaceDocPolicy::Export
+                    && f.export_type(self.fs.export_format).is_some())
+        });
+        // an export can take the name of a real file, e.g. doc `Report` & file `Report.docx`
+        let mut files = files
+            .into_iter()
+            .map(|f| (f, self.tree_name(f)))
+            .collect::<Vec<_>>();
+        files.sort_by(|a, b| a.1.cmp(&b.1));
+        let mut taken = files
+            .iter()
+            .map(|(_, name)| name.clone())
+            .collect::<HashSet<_>>();
+        let mut resolved = skipped_docs
+            .into_iter()
+            .map(|f| (f, f.name.clone()))
+            .collect::<Vec<_>>();
+
+        for duplicates in files.chunk_by(|a, b| a.1 == b.1) {
+            let [(gd_file, name)] = duplicates else {
+                let name = &duplicates[0].1;
+                let path = parent_path.join(name);
+                let ids = duplicates
+                    .iter()
+                    .map(|(f, _)| f.id.clone())
+                    .collect::<Vec<_>>();
+                match self.fs.duplicate_policy {
+                    DuplicatePolicy::Error => {
+                        return Err(Error::from(GDError::DuplicateName {
+                            path: path.display().to_string(),
+                            ids,
+                        }))
+                    }
+                    DuplicatePolicy::Newest => {
+                        let (newest, _) = duplicates
+                            .iter()
+                            .max_by_key(|(f, _)| f.modified_time)
+                            .unwrap();
+                        warn!(
+                            "{} files are named {}, only syncing the newest one {}",
+                            ids.len(),
+                            path.display(),
+                            newest.id
+                        );
+                        for (gd_file, _) in duplicates.iter().filter(|(f, _)| f.id != newest.id) {
+                            self.visit_subtree(gd_file);
+                        }
+                        resolved.push((newest, name.clone()));
+                    }
+                    DuplicatePolicy::Rename => {
+                        warn!(
+                            "{} files are named {}, renaming them in the tree",
+                            ids.len(),
+                            path.display()
+                        );
+                        // ordered by id, so that each file keeps its name between runs
+                        let mut duplicates = duplicates.to_vec();
+                        duplicates.sort_by(|a, b| a.0.id.cmp(&b.0.id));
+                        resolved.push((duplicates[0].0, name.clone()));
+                        for (gd_file, _) in &duplicates[1..] {
+                            let name = duplicate_name(name, gd_file.is_dir(), &taken);
+                            taken.insert(name.clone());
+                            resolved.push((gd_file, name));
+                        }
+                    }
+                }
+                continue;
+            };
+            resolved.push((gd_file, name.clone()));
+        }
+
+        resolved.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(resolved)
+    }
+
+    // Mark a file left out of the tree & everything under it as visited
+    fn visit_subtree(&mut self, gd_file: &GDFile) {
+        if !self.visited.insert(gd_file.id.clone()) {
+            return;
+        }
+        let files = self
+            .children
+            .get(gd_file.id.as_str())
+            .cloned()
+            .unwrap_or_default();
+        for child in files {
+            self.visit_subtree(child);
+        }
+    }
+
+    fn workspace_doc_node(
+        &mut self,
+        gd_file: &GDFile,
+        name: &str,
+        parent_path: &Path,
+    ) -> Option<Node> {
+        let path = parent_path.join(name);
+        let export_type = match self.fs.docs_policy {
+            WorkspaceDocPolicy::Export => gd_file.export_type(self.fs.export_format),
+            WorkspaceDocPolicy::Skip | WorkspaceDocPolicy::Opaque => None,
+        };
+        // their folders are never removed as a whole, see rm
+        if export_type.is_some() || self.fs.docs_policy == WorkspaceDocPolicy::Opaque {
+            self.opaque_dirs
+                .extend(parent_path.ancestors().map(Path::to_path_buf));
+        }
+
+        let Some((_, extension)) = export_type else {
+            if self.fs.docs_policy == WorkspaceDocPolicy::Export {
+                warn!("Cannot export {} ({})", path.display(), gd_file.mime_type);
+            }
+            debug!("Skipping Google Workspace doc {}", path.display());
+            return None;
+        };
+
+        self.path_to_meta.insert(path.clone(), gd_file.clone());
+        let (content_hash, size) = match self.exported_hashes.get(&gd_file.id) {
+            Some(exported) if exported.matches(gd_file, extension) => {
+                (exported.content_hash, exported.size)
+            }
+            // only known after exporting
+            _ => (gd_file.synthetic_hash(extension), 0),
+        };
+        Some(Node {
+            node_type: NodeType::File,
+            name: name.to_string(),
+            path,
+            updated_at: gd_file.modified_time,
+            content_hash,
+            children: vec![],
+            size,
+            inode: None,
+        })
+    }
+}
+
+// Find a free name for a duplicate: `name (duplicate).ext`, same as conflict copies
+fn duplicate_name(name: &str, is_dir: bool, taken: &HashSet<String>) -> String {
+    let path = Path::new(name);
+    let (stem, ext) = match (is_dir, path.file_stem(), path.extension()) {
+        (false, Some(stem), Some(ext)) => (
+            stem.to_string_lossy().to_string(),
+            format!(".{}", ext.to_string_lossy()),
+        ),
+        _ => (name.to_string(), String::new()),
+    };
+    let mut counter = 1;
+    loop {
+        let suffix = if counter == 1 {
+            String::from("duplicate")
+        } else {
+            format!("duplicate {counter}")
+        };
+        let name = format!("{stem} ({suffix}){ext}");
+        if !taken.contains(&name) {
+            return name;
+        }
+        counter += 1;
+    }
+}
+
 #[async_trait]
 impl FileSystem for GoogleDriveFileSystem {
     async fn write(&self, path: &Path, content: &[u8]) -> Result<()> {
+        self.write_stream(path, content.to_vec().into()).await
+    }
+
+    async fn write_stream(&self, path: &Path, stream: ReadStream) -> Result<()> {
         self.init().await?;
 
         // check parent is dir
@@ -468,8 +1664,22 @@ impl FileSystem for GoogleDriveFileSystem {
         };
         parent_meta.assert_is_dir()?;
 
-        // decide whether to create or update
+        // decide whether to create or update.
+        // Exported workspace docs are read-only, binary content would replace the doc itself
         let gd_meta = path_to_meta.get(path);
+        if gd_meta.is_some_and(GDFile::is_workspace_doc) {
+            warn!(
+                "{} is an exported Google Workspace doc, not overwriting it",
+                path.display()
+            );
+            return Ok(());
+        }
+        let mut body = json!({});
+        if let Some(updated_at) = stream.updated_at {
+            body["modifiedTime"] = updated_at
+                .to_rfc3339_opts(SecondsFormat::Millis, true)
+                .into();
+        }
         let req_builder = if let Some(gd_meta) = gd_meta {
             debug!("Updating file at {}", path.display());
             self.http_client
@@ -477,25 +1687,16 @@ impl FileSystem for GoogleDriveFileSystem {
         } else {
             debug!("Creating file at {}", path.display());
             let name = path.file_name().unwrap().to_str().unwrap();
-            let body = json!({
-                "name": name,
-                "parents": [parent_meta.id.as_str()],
-            });
-            self.http_client
-                .post(GOOGLE_DRIVE_UPLOAD_API_URL)
-                .json(&body)
+            body["name"] = name.into();
+            body["parents"] = json!([parent_meta.id.as_str()]);
+            self.http_client.post(GOOGLE_DRIVE_UPLOAD_API_URL)
         };
+        let req_builder = req_builder.json(&body);
         drop(path_to_meta);
 
         // make first request to acquire the upload session url
-        let headers = self.auth_header().await?;
         let query = [("uploadType", "resumable")];
-        let res = req_builder
-            .headers(headers)
-            .query(&query)
-            .send()
-            .await?
-            .error_for_status()?;
+        let res = self.send(req_builder.query(&query)).await?;
         let res_headers = res.headers();
         let Some(location) = res_headers.get("location") else {
             return Err(Error::from(GDError::MissingField {
@@ -505,23 +1706,8 @@ impl FileSystem for GoogleDriveFileSystem {
         debug!("Upload url {:?}", location);
 
         // actually upload the file
-        // TODO upload in chunk for large file
-        let content = Vec::from(content);
-        let content_len = content.len().to_string();
-        let header_value =
-            HeaderValue::from_str(&content_len).map_err(|e| Error::Unknown(anyhow!(e)))?;
-        let mut headers = self.auth_header().await?;
-        headers.append("content-length", header_value);
-        let file_meta: serde_json::Value = self
-            .http_client
-            .put(location.to_str().unwrap())
-            .headers(headers)
-            .body(content)
-            .send()
-            .await?
-            .error_for_status()?
-            .json()
-            .await?;
+        let session_url = location.to_str().unwrap().to_string();
+        let file_meta = self.upload_chunks(&session_url, stream).await?;
         debug!("Upload response: {:?}", file_meta);
 
         // file_meta doesn't contain all fields we need
@@ -537,6 +1723,14 @@ impl FileSystem for GoogleDriveFileSystem {
     }
 
     async fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut stream = self.open_read(path).await?;
+        let mut content = Vec::with_capacity(stream.size as usize);
+        stream.reader.read_to_end(&mut content).await?;
+        debug!("Downloaded file size: {}", content.len());
+        Ok(content)
+    }
+
+    async fn open_read(&self, path: &Path) -> Result<ReadStream> {
         self.init().await?;
 
         let pb = path.to_path_buf();
@@ -548,26 +1742,18 @@ impl FileSystem for GoogleDriveFileSystem {
         };
         debug!("Reading file {:?}", file_meta);
 
-        let url = format!("{}/files/{}", GOOGLE_DRIVE_API_URL, file_meta.id);
-        let query = (
-            ("alt", "media"),
-            ("acknowledgeAbuse", "true"),
-            ("supportsAllDrives", "true"),
-        );
-        drop(path_to_meta);
-        let headers = self.auth_header().await?;
+        // exports are limited to 10MB, no need to stream them
+        if file_meta.is_workspace_doc() {
+            let file_meta = file_meta.clone();
+            drop(path_to_meta);
+            let content = self.export(&file_meta).await?;
+            self.save_exported_hash(&file_meta, &content).await;
+            return Ok(content.into());
+        }
 
-        let response = self
-            .http_client
-            .get(url)
-            .headers(headers)
-            .query(&query)
-            .send()
-            .await?
-            .bytes()
-            .await?;
-        debug!("Downloaded file size: {}", response.len());
-        Ok(response.into())
+        let stream = self.download_stream(file_meta);
+        drop(path_to_meta);
+        Ok(stream)
     }
 
     async fn mkdir(&self, path: &Path) -> Result<()> {
@@ -608,16 +1794,9 @@ impl FileSystem for GoogleDriveFileSystem {
             debug!("BODY: {body:?}");
             drop(path_to_meta);
             let url = format!("{GOOGLE_DRIVE_API_URL}/files");
-            let headers = self.auth_header().await?;
             let query = [("fields", "id, name, mimeType, modifiedTime")];
-            let response = self
-                .http_client
-                .post(url)
-                .headers(headers)
-                .query(&query)
-                .json(&body)
-                .send()
-                .await?;
+            let req_builder = self.http_client.post(url).query(&query).json(&body);
+            let response = self.send(req_builder).await?;
             debug!("Got response status: {}", response.status());
 
             let child_meta = response.json().await?;
@@ -634,22 +1813,37 @@ impl FileSystem for GoogleDriveFileSystem {
         self.init().await?;
 
         let path_to_meta = self.path_to_meta.read().await;
-        let Some(GDFile { id, .. }) = path_to_meta.get(path) else {
+        let Some(gd_meta) = path_to_meta.get(path) else {
             return Err(Error::from(GDError::FileNotFound {
                 file: path.display().to_string(),
             }));
         };
-        debug!("Removing file {path:?} with id {id:?}");
+        if gd_meta.is_workspace_doc() {
+            warn!(
+                "{} is an exported Google Workspace doc, not deleting it",
+                path.display()
+            );
+            return Ok(());
+        }
+        let id = gd_meta.id.clone();
+
+        if self.opaque_dirs.read().await.contains(path) {
+            debug!("{path:?} contains Google Workspace docs, removing the other files only");
+            let children = path_to_meta
+                .keys()
+                .filter(|child| child.parent() == Some(path))
+                .cloned()
+                .collect::<Vec<_>>();
+            drop(path_to_meta);
+            for child in children {
+                Box::pin(self.rm(&child)).await?;
+            }
+            return Ok(());
+        }
 
-        let url = format!("{GOOGLE_DRIVE_API_URL}/files/{id}");
+        debug!("Removing file {path:?} with id {id:?}");
         drop(path_to_meta);
-        let headers = self.auth_header().await?;
-        self.http_client
-            .delete(url)
-            .headers(headers)
-            .send()
-            .await?
-            .error_for_status()?;
+        self.delete_by_id(&id).await?;
 
         let mut path_to_meta = self.path_to_meta.write().await;
         path_to_meta.remove(path);
@@ -660,6 +1854,13 @@ impl FileSystem for GoogleDriveFileSystem {
         self.init().await?;
 
         let path_to_meta = self.path_to_meta.read().await;
+        if path_to_meta.get(dest).is_some_and(GDFile::is_workspace_doc) {
+            warn!(
+                "{} is an exported Google Workspace doc, not replacing it",
+                dest.display()
+            );
+            return Ok(());
+        }
         if path_to_meta.contains_key(dest) {
             debug!("File/folder at {dest:?} exists. Removing");
             self.rm(dest).await?;
@@ -712,15 +1913,8 @@ impl FileSystem for GoogleDriveFileSystem {
             "name": dest.file_name().unwrap().to_str().unwrap(),
         });
         drop(path_to_meta);
-        let headers = self.auth_header().await?;
-        let res = self
-            .http_client
-            .patch(url)
-            .headers(headers)
-            .query(&query)
-            .json(&body)
-            .send()
-            .await?;
+        let req_builder = self.http_client.patch(url).query(&query).json(&body);
+        let res = self.send(req_builder).await?;
 
         let new_meta: GDFile = res.json().await?;
         let mut path_to_meta = self.path_to_meta.write().await;
@@ -732,14 +1926,717 @@ impl FileSystem for GoogleDriveFileSystem {
     async fn build_tree(&self) -> Result<Node> {
         let root_dir_id = self.get_root_dir_id().await?;
         debug!("Root dir id: {}", root_dir_id);
+        let root_meta = self.metadata(&root_dir_id).await?;
+        if !root_meta.is_dir() {
+            return Err(Error::ExpectDirectory(self.root_dir.clone()));
+        }
 
-        let node = self.build_node(&root_dir_id, "".as_ref(), true).await?;
+        let cached = match self.load_cache(&root_dir_id).await {
+            Some(mut cache) => match self.update_cache(&mut cache).await {
+                Ok(()) => Some(cache),
+                Err(e) => {
+                    warn!("Cannot apply Google Drive changes, listing every folder: {e}");
+                    None
+                }
+            },
+            None => None,
+        };
+        let mut cache = match cached {
+            Some(cache) => cache,
+            None => {
+                // taken before listing, so that changes made while listing aren't missed
+                let start_page_token = self.start_page_token().await?;
+                let mut files = HashMap::new();
+                self.list_subtree(&root_dir_id, &mut files).await?;
+                TreeCache {
+                    root_id: root_dir_id,
+                    start_page_token,
+                    files,
+                }
+            }
+        };
 
-        match node.node_type {
-            NodeType::File => Err(Error::ExpectDirectory(self.root_dir.clone())),
-            NodeType::Directory => Ok(node),
+        let exported_hashes = self.load_exported_hashes().await;
+        let mut builder = TreeBuilder::new(self, &cache.files, &exported_hashes);
+        let node = builder.build_node(&root_meta, PathBuf::from(""))?;
+        let TreeBuilder {
+            path_to_meta,
+            opaque_dirs,
+            visited,
+            ..
+        } = builder;
+        *self.path_to_meta.write().await = path_to_meta;
+        *self.opaque_dirs.write().await = opaque_dirs;
+        *self.exported_hashes.write().await = exported_hashes;
+
+        // changes outside of the root are listed too, don't keep them
+        cache.files.retain(|id, _| visited.contains(id));
+        if let Err(e) = self.save_cache(&cache).await {
+            warn!("Cannot save Google Drive tree cache: {e}");
         }
+        Ok(node)
     }
 }
 
 // endregion
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex as StdMutex;
+
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::oauth::TokenType;
+
+    // A local HTTP server answering the n-th request with respond(n, request head).
+    // Requests are expected without body, every response closes its connection
+    struct MockServer {
+        url: String,
+        // request heads, in order
+        requests: Arc<StdMutex<Vec<String>>>,
+    }
+
+    impl MockServer {
+        async fn start(respond: impl Fn(usize, &str) -> String + Send + 'static) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(StdMutex::new(vec![]));
+            let received = requests.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    let mut head = vec![];
+                    let mut buf = [0; 1024];
+                    while !head.ends_with(b"\r\n\r\n") {
+                        let n = socket.read(&mut buf).await.unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        head.extend_from_slice(&buf[..n]);
+                    }
+                    let head = String::from_utf8_lossy(&head).to_lowercase();
+                    let n = {
+                        let mut received = received.lock().unwrap();
+                        received.push(head.clone());
+                        received.len() - 1
+                    };
+                    socket
+                        .write_all(respond(n, &head).as_bytes())
+                        .await
+                        .unwrap();
+                    let _ = socket.shutdown().await;
+                }
+            });
+            Self { url, requests }
+        }
+
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    fn http_response(status: &str, headers: &[(&str, &str)], body: &str) -> String {
+        let mut response = format!(
+            "HTTP/1.1 {status}\r\ncontent-length: {}\r\nconnection: close\r\n",
+            body.len()
+        );
+        for (name, value) in headers {
+            response.push_str(&format!("{name}: {value}\r\n"));
+        }
+        response + "\r\n" + body
+    }
+
+    // Drive API error body, e.g. for a rate limit
+    fn error_body(reason: &str) -> String {
+        json!({
+            "error": {
+                "code": 403,
+                "message": reason,
+                "errors": [{ "reason": reason }],
+            }
+        })
+        .to_string()
+    }
+
+    fn test_fs() -> GoogleDriveFileSystem {
+        let config_dir = std::env::temp_dir().join("crustasync-test-gd");
+        let cache_file = config_dir.join(CACHE_DIR_NAME).join("root.json");
+        GoogleDriveFileSystem {
+            auth_token: Arc::new(RwLock::new(AuthToken {
+                access_token: "token-0".to_string(),
+                refresh_token: "refresh".to_string(),
+                expires_at: Utc::now() + TimeDelta::hours(1),
+                token_type: TokenType::Bearer,
+                scope: HashSet::new(),
+                id_token: String::new(),
+            })),
+            token_file: config_dir.join(CONFIG_FILE_NAME),
+            http_client: ReqwestClient::new(),
+            root_dir: PathBuf::from("/"),
+            path_to_meta: Arc::new(RwLock::new(HashMap::new())),
+            initialized: Arc::new(Mutex::new(false)),
+            upload_chunk_size: 256 * 1024,
+            docs_policy: WorkspaceDocPolicy::Skip,
+            export_format: ExportFormat::Office,
+            duplicate_policy: DuplicatePolicy::Error,
+            permanent_delete: false,
+            opaque_dirs: Arc::new(RwLock::new(HashSet::new())),
+            exported_hashes: Arc::new(RwLock::new(HashMap::new())),
+            drive_id: None,
+            root_id: None,
+            exports_file: cache_file.with_extension("exports.json"),
+            cache_file,
+            full_rehash: false,
+            requests: RequestLimit::new(4),
+        }
+    }
+
+    fn gd_dir(id: &str, name: &str, parent: &str) -> GDFile {
+        GDFile {
+            id: id.to_string(),
+            name: name.to_string(),
+            mime_type: GOOGLE_DRIVE_FOLDER_MIME_TYPE.to_string(),
+            modified_time: DateTime::UNIX_EPOCH,
+            sha256_checksum: None,
+            size: None,
+            version: None,
+            parents: vec![parent.to_string()],
+            trashed: false,
+            drive_id: None,
+        }
+    }
+
+    fn gd_file(id: &str, name: &str, parent: &str) -> GDFile {
+        GDFile {
+            mime_type: "text/plain".to_string(),
+            sha256_checksum: Some(hex::encode(Sha256::digest(id))),
+            size: Some("1".to_string()),
+            ..gd_dir(id, name, parent)
+        }
+    }
+
+    fn by_id(files: Vec<GDFile>) -> HashMap<String, GDFile> {
+        files
+            .into_iter()
+            .map(|file| (file.id.clone(), file))
+            .collect()
+    }
+
+    // The tree under the `root` folder, along with the id of each path
+    fn build_tree(
+        fs: &GoogleDriveFileSystem,
+        files: &HashMap<String, GDFile>,
+    ) -> Result<(Node, HashMap<PathBuf, String>)> {
+        let root = GDFile {
+            parents: vec![],
+            ..gd_dir("root", "root", "")
+        };
+        let exported_hashes = HashMap::new();
+        let mut builder = TreeBuilder::new(fs, files, &exported_hashes);
+        let node = builder.build_node(&root, PathBuf::new())?;
+        let ids = builder
+            .path_to_meta
+            .into_iter()
+            .map(|(path, meta)| (path, meta.id))
+            .collect();
+        Ok((node, ids))
+    }
+
+    fn paths(tree: &Node) -> Vec<String> {
+        let mut paths = tree
+            .into_iter()
+            .filter(|node| !node.path.as_os_str().is_empty())
+            .map(|node| node.path.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    async fn error_for(status: &str, headers: &[(&str, &str)], body: &str) -> GDError {
+        let response = http_response(status, headers, body);
+        let server = MockServer::start(move |_, _| response.clone()).await;
+        GDError::from_response(reqwest::get(&server.url).await.unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn rate_limits_and_server_errors_are_transient() {
+        for status in [
+            "429 Too Many Requests",
+            "408 Request Timeout",
+            "500 Internal Server Error",
+            "503 Service Unavailable",
+        ] {
+            let error = error_for(status, &[], "").await;
+            assert!(matches!(error, GDError::Transient { .. }), "{status}");
+        }
+        for reason in ["userRateLimitExceeded", "rateLimitExceeded"] {
+            let error = error_for("403 Forbidden", &[], &error_body(reason)).await;
+            assert!(matches!(error, GDError::Transient { .. }), "{reason}");
+        }
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_permanent() {
+        for status in ["400 Bad Request", "404 Not Found"] {
+            let error = error_for(status, &[], "").await;
+            assert!(matches!(error, GDError::Permanent { .. }), "{status}");
+        }
+        let error = error_for(
+            "403 Forbidden",
+            &[],
+            &error_body("insufficientFilePermissions"),
+        )
+        .await;
+        let GDError::Permanent { status, message } = error else {
+            panic!("expect a permanent error, got {error}");
+        };
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(message, "insufficientFilePermissions");
+    }
+
+    #[tokio::test]
+    async fn transient_errors_keep_retry_after() {
+        let error = error_for("503 Service Unavailable", &[("retry-after", "30")], "").await;
+        let GDError::Transient { retry_after, .. } = error else {
+            panic!("expect a transient error, got {error}");
+        };
+        assert_eq!(retry_after, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn parse_retry_after_reads_seconds_and_dates() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after(" 0 "), Some(Duration::ZERO));
+
+        let date = (Utc::now() + TimeDelta::seconds(60))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let delay = parse_retry_after(&date).unwrap();
+        assert!(
+            Duration::from_secs(58) <= delay && delay <= Duration::from_secs(60),
+            "{delay:?}"
+        );
+
+        // already passed
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        for value in ["", "-1", "soon", "1.5"] {
+            assert_eq!(parse_retry_after(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn backoff_delay_stays_within_bounds() {
+        let jitter = Duration::from_secs(1);
+        for _ in 0..100 {
+            for (retries, delay) in [(1, 1), (2, 2), (3, 4), (5, 16), (7, 64), (40, 64)] {
+                let min = Duration::from_secs(delay);
+                let backoff = backoff_delay(retries, None);
+                assert!(min <= backoff && backoff < min + jitter, "{backoff:?}");
+            }
+            let min = Duration::from_secs(30);
+            let backoff = backoff_delay(1, Some(min));
+            assert!(min <= backoff && backoff < min + jitter, "{backoff:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn only_transient_errors_are_retried() {
+        let fs = test_fs();
+        let server = MockServer::start(|n, _| match n {
+            0 => http_response("503 Service Unavailable", &[("retry-after", "0")], ""),
+            _ => http_response("200 OK", &[], "{}"),
+        })
+        .await;
+        fs.send(fs.http_client.get(&server.url)).await.unwrap();
+        assert_eq!(server.requests().len(), 2);
+
+        let server = MockServer::start(|_, _| http_response("404 Not Found", &[], "")).await;
+        fs.send(fs.http_client.get(&server.url)).await.unwrap_err();
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn duplicate_name_keeps_the_extension_of_files() {
+        let taken = HashSet::new();
+        assert_eq!(
+            duplicate_name("report.pdf", false, &taken),
+            "report (duplicate).pdf"
+        );
+        assert_eq!(
+            duplicate_name("Makefile", false, &taken),
+            "Makefile (duplicate)"
+        );
+        assert_eq!(
+            duplicate_name("photos.2024", true, &taken),
+            "photos.2024 (duplicate)"
+        );
+
+        let taken = HashSet::from([
+            "report (duplicate).pdf".to_string(),
+            "report (duplicate 2).pdf".to_string(),
+        ]);
+        assert_eq!(
+            duplicate_name("report.pdf", false, &taken),
+            "report (duplicate 3).pdf"
+        );
+    }
+
+    #[test]
+    fn rename_policy_keeps_every_duplicate() {
+        let mut fs = test_fs();
+        fs.duplicate_policy = DuplicatePolicy::Rename;
+        let files = by_id(vec![
+            gd_file("a1", "a.txt", "root"),
+            gd_file("a2", "a.txt", "root"),
+            gd_file("a3", "a.txt", "root"),
+            // a real file already has the first free name
+            gd_file("b", "a (duplicate).txt", "root"),
+            gd_dir("d1", "dir", "root"),
+            gd_dir("d2", "dir", "root"),
+            gd_file("c", "c", "d2"),
+        ]);
+        let (tree, ids) = build_tree(&fs, &files).unwrap();
+        assert_eq!(
+            paths(&tree),
+            [
+                "a (duplicate 2).txt",
+                "a (duplicate 3).txt",
+                "a (duplicate).txt",
+                "a.txt",
+                "dir",
+                "dir (duplicate)",
+                "dir (duplicate)/c",
+            ]
+        );
+        // ordered by id, so that the names don't change between runs
+        for (path, id) in [
+            ("a.txt", "a1"),
+            ("a (duplicate 2).txt", "a2"),
+            ("a (duplicate 3).txt", "a3"),
+            ("a (duplicate).txt", "b"),
+            ("dir (duplicate)", "d2"),
+        ] {
+            assert_eq!(ids[Path::new(path)], id, "{path}");
+        }
+    }
+
+    #[test]
+    fn newest_policy_keeps_the_latest_modified_file() {
+        let mut fs = test_fs();
+        fs.duplicate_policy = DuplicatePolicy::Newest;
+        let modified = |mut file: GDFile, seconds| {
+            file.modified_time = DateTime::from_timestamp(seconds, 0).unwrap();
+            file
+        };
+        let files = by_id(vec![
+            modified(gd_file("a1", "a.txt", "root"), 20),
+            modified(gd_file("a2", "a.txt", "root"), 30),
+            modified(gd_file("a3", "a.txt", "root"), 10),
+            modified(gd_dir("d1", "dir", "root"), 20),
+            modified(gd_dir("d2", "dir", "root"), 10),
+            gd_file("c", "c", "d2"),
+        ]);
+        let (tree, ids) = build_tree(&fs, &files).unwrap();
+        assert_eq!(paths(&tree), ["a.txt", "dir"]);
+        assert_eq!(ids[Path::new("a.txt")], "a2");
+        assert_eq!(ids[Path::new("dir")], "d1");
+    }
+
+    #[test]
+    fn error_policy_refuses_duplicates() {
+        let fs = test_fs();
+        let files = by_id(vec![
+            gd_file("a", "a.txt", "root"),
+            gd_dir("d", "dir", "root"),
+            gd_file("b1", "b.txt", "d"),
+            gd_file("b2", "b.txt", "d"),
+        ]);
+        let error = build_tree(&fs, &files).unwrap_err();
+        let Error::GoogleDrive(GDError::DuplicateName { path, mut ids }) = error else {
+            panic!("expect a duplicate name error, got {error}");
+        };
+        ids.sort();
+        assert_eq!(path, "dir/b.txt");
+        assert_eq!(ids, ["b1", "b2"]);
+
+        let files = by_id(vec![
+            gd_file("a", "a.txt", "root"),
+            gd_file("b", "b.txt", "root"),
+        ]);
+        assert_eq!(
+            paths(&build_tree(&fs, &files).unwrap().0),
+            ["a.txt", "b.txt"]
+        );
+    }
+
+    // Every path of the tree with the content it has
+    fn listing(tree: &Node) -> Vec<(String, String, u64)> {
+        let mut listing = tree
+            .into_iter()
+            .map(|node| {
+                let path = node.path.to_string_lossy().to_string();
+                (path, hex::encode(node.content_hash), node.size)
+            })
+            .collect::<Vec<_>>();
+        listing.sort();
+        listing
+    }
+
+    #[test]
+    fn cached_tree_follows_changes() {
+        let fs = test_fs();
+        let mut cache = TreeCache {
+            root_id: "root".to_string(),
+            start_page_token: "1".to_string(),
+            files: by_id(vec![
+                gd_file("a", "a.txt", "root"),
+                gd_file("t", "t.txt", "root"),
+                gd_dir("docs", "docs", "root"),
+                gd_file("b", "b.txt", "docs"),
+                gd_dir("old", "old", "root"),
+                gd_dir("old-sub", "sub", "old"),
+                gd_file("c", "c.txt", "old-sub"),
+            ]),
+        };
+        let changed = |file: GDFile| GDChange {
+            file_id: Some(file.id.clone()),
+            removed: false,
+            file: Some(file),
+        };
+        let new_folders = cache.apply_changes(vec![
+            // moved out of the root
+            changed(gd_file("a", "a.txt", "elsewhere")),
+            changed(GDFile {
+                trashed: true,
+                ..gd_file("t", "t.txt", "root")
+            }),
+            // deleted for good, without a change for its content
+            GDChange {
+                file_id: Some("old".to_string()),
+                removed: true,
+                file: None,
+            },
+            changed(gd_dir("new", "new", "docs")),
+            changed(gd_file("e", "e.txt", "new")),
+            changed(gd_file("x", "x.txt", "elsewhere")),
+            // shared drive changes have no file
+            GDChange {
+                file_id: None,
+                removed: false,
+                file: None,
+            },
+        ]);
+        assert_eq!(new_folders, ["new"]);
+
+        // a full listing of the drive after the changes
+        let files = by_id(vec![
+            gd_dir("docs", "docs", "root"),
+            gd_file("b", "b.txt", "docs"),
+            gd_dir("new", "new", "docs"),
+            gd_file("e", "e.txt", "new"),
+        ]);
+        let (fresh_tree, _) = build_tree(&fs, &files).unwrap();
+        let (cached_tree, _) = build_tree(&fs, &cache.files).unwrap();
+        assert_eq!(listing(&cached_tree), listing(&fresh_tree));
+        assert_eq!(
+            paths(&fresh_tree),
+            ["docs", "docs/b.txt", "docs/new", "docs/new/e.txt"]
+        );
+    }
+
+    #[test]
+    fn is_under_follows_every_parent() {
+        let files = by_id(vec![
+            gd_dir("a", "a", "root"),
+            gd_dir("b", "b", "a"),
+            gd_dir("out", "out", "elsewhere"),
+            gd_dir("in-out", "in-out", "out"),
+            // parents edited concurrently, each folder in the other
+            gd_dir("loop1", "loop1", "loop2"),
+            gd_dir("loop2", "loop2", "loop1"),
+            // files can have several parents
+            GDFile {
+                parents: vec!["out".to_string(), "b".to_string()],
+                ..gd_file("f", "f", "")
+            },
+        ]);
+        for (id, is_under) in [
+            ("a", true),
+            ("b", true),
+            ("out", false),
+            ("in-out", false),
+            ("loop1", false),
+            ("f", true),
+        ] {
+            assert_eq!(
+                GoogleDriveFileSystem::is_under(&files[id], "root", &files),
+                is_under,
+                "{id}"
+            );
+        }
+    }
+
+    async fn upload_status(response: String) -> reqwest::Result<UploadStatus> {
+        let server = MockServer::start(move |_, _| response.clone()).await;
+        UploadStatus::from_response(reqwest::get(&server.url).await.unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn upload_status_reads_the_received_range() {
+        let response = http_response("308 Resume Incomplete", &[("range", "bytes=0-262143")], "");
+        let Ok(UploadStatus::Received(received)) = upload_status(response).await else {
+            panic!("expect the number of bytes received");
+        };
+        assert_eq!(received, 262144);
+
+        // nothing received yet, the upload starts over
+        let response = http_response("308 Resume Incomplete", &[], "");
+        let Ok(UploadStatus::Received(received)) = upload_status(response).await else {
+            panic!("expect the number of bytes received");
+        };
+        assert_eq!(received, 0);
+    }
+
+    #[tokio::test]
+    async fn upload_status_is_done_with_the_file_metadata() {
+        for status in ["200 OK", "201 Created"] {
+            let response = http_response(status, &[], r#"{"id":"file-id"}"#);
+            let Ok(UploadStatus::Done(file_meta)) = upload_status(response).await else {
+                panic!("expect the upload to be done, {status}");
+            };
+            assert_eq!(file_meta["id"], "file-id");
+        }
+
+        let response = http_response("404 Not Found", &[], "");
+        let Err(error) = upload_status(response).await else {
+            panic!("expect an expired upload session to fail");
+        };
+        assert_eq!(error.status(), Some(StatusCode::NOT_FOUND));
+    }
+
+    fn download(fs: &GoogleDriveFileSystem, url: &str, content: &str) -> Download {
+        Download {
+            fs: fs.clone(),
+            url: url.to_string(),
+            name: "file".to_string(),
+            size: content.len() as u64,
+            expected_hash: Some(Sha256::digest(content).into()),
+            offset: 0,
+            hasher: Sha256::new(),
+            response: None,
+            retries: 0,
+        }
+    }
+
+    async fn read_all(download: &mut Download) -> std::io::Result<String> {
+        let mut content = vec![];
+        while let Some(chunk) = download.next_chunk().await? {
+            content.extend_from_slice(&chunk);
+        }
+        Ok(String::from_utf8(content).unwrap())
+    }
+
+    #[tokio::test]
+    async fn download_resumes_after_a_partial_body() {
+        let fs = test_fs();
+        // a body shorter than the file, then a connection dropped before content-length
+        let truncated = "HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\n0123".to_string();
+        for first in [http_response("200 OK", &[], "0123"), truncated] {
+            let server = MockServer::start(move |n, _| match n {
+                0 => first.clone(),
+                _ => http_response("206 Partial Content", &[], "456789"),
+            })
+            .await;
+            let mut download = download(&fs, &server.url, "0123456789");
+            assert_eq!(read_all(&mut download).await.unwrap(), "0123456789");
+
+            let requests = server.requests();
+            assert_eq!(requests.len(), 2);
+            assert!(!requests[0].contains("range:"));
+            assert!(requests[1].contains("range: bytes=4-\r\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn download_gives_up_after_max_retries() {
+        let fs = test_fs();
+        let server = MockServer::start(|n, _| match n {
+            0 => http_response("200 OK", &[], "0"),
+            _ => http_response("206 Partial Content", &[], ""),
+        })
+        .await;
+        let mut download = download(&fs, &server.url, "0123456789");
+        let error = read_all(&mut download).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(
+            server.requests().len(),
+            1 + GOOGLE_DRIVE_MAX_RETRIES as usize
+        );
+    }
+
+    #[tokio::test]
+    async fn download_rejects_a_range_ignored_by_the_server() {
+        let fs = test_fs();
+        let server = MockServer::start(|n, _| match n {
+            0 => http_response("200 OK", &[], "0123"),
+            _ => http_response("200 OK", &[], "0123456789"),
+        })
+        .await;
+        let mut download = download(&fs, &server.url, "0123456789");
+        assert!(read_all(&mut download).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn download_rejects_a_checksum_mismatch() {
+        let fs = test_fs();
+        let server = MockServer::start(|_, _| http_response("200 OK", &[], "corrupted!")).await;
+        let mut download = download(&fs, &server.url, "0123456789");
+        let error = read_all(&mut download).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn rejected_token_is_refreshed_once() {
+        // the token is replaced as if another request had already refreshed it,
+        // so that refreshing doesn't need the real token endpoint
+        let fs = test_fs();
+        let auth_token = fs.auth_token.clone();
+        let refreshes = Arc::new(AtomicUsize::new(0));
+        let rejected = move || {
+            let n = refreshes.fetch_add(1, Ordering::SeqCst) + 1;
+            auth_token.try_write().unwrap().access_token = format!("token-{n}");
+            http_response("401 Unauthorized", &[], "")
+        };
+
+        let reject_once = rejected.clone();
+        let server = MockServer::start(move |n, _| match n {
+            0 => reject_once(),
+            _ => http_response("200 OK", &[], "{}"),
+        })
+        .await;
+        fs.send(fs.http_client.get(&server.url)).await.unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].contains("authorization: bearer token-0"));
+        assert!(requests[1].contains("authorization: bearer token-1"));
+
+        let server = MockServer::start(move |_, _| rejected()).await;
+        let error = fs.send(fs.http_client.get(&server.url)).await.unwrap_err();
+        assert!(
+            matches!(
+                error,
+                Error::GoogleDrive(GDError::Permanent {
+                    status: StatusCode::UNAUTHORIZED,
+                    ..
+                })
+            ),
+            "{error}"
+        );
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("authorization: bearer token-2"));
+    }
+}
@@ -1,26 +1,42 @@
+use std::collections::HashMap;
+use std::fs::Metadata;
 use std::path::{Path, PathBuf};
 
 use async_trait::async_trait;
-use chrono::DateTime;
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
 use sha2::{Digest, Sha256};
 use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use uuid::Uuid;
 
-use crate::crustasyncfs::base::{FileSystem, Node, NodeType, CRUSTASYNC_CONFIG_FILE};
+use crate::crustasyncfs::base::{
+    ContentHash, FileSystem, Node, NodeType, ReadStream, CRUSTASYNC_CONFIG_FILE,
+};
 use crate::error::{Error, Result};
 
+// Files are written next to their target under this prefix, then renamed into place.
+// Leftovers of interrupted writes are removed when building the tree
+const PARTIAL_FILE_PREFIX: &str = ".crustasync-partial-";
+
+// Tree as last built on this side, to reuse hashes of unchanged files.
+// Kept apart from .crustasync, which holds the sync state & is overwritten with the other side's tree
+const HASH_CACHE_FILE: &str = ".crustasync-hashes";
+
 #[derive(Debug, Clone)]
 pub struct LocalFileSystem {
     pub(crate) root_dir: PathBuf,
+    // ignore the cached hashes & hash every file again
+    full_rehash: bool,
 }
 
+// Files of the previously built tree, by path
+type HashCache = HashMap<PathBuf, Node>;
+
 #[async_trait]
 impl FileSystem for LocalFileSystem {
-    async fn write(&self, path: &Path, content: &[u8]) -> Result<()> {
-        let path_buf = self.abs_path(path);
-        let parent = path_buf.parent().unwrap();
-        fs::create_dir_all(parent).await?;
-        fs::write(path_buf, content).await?;
-        Ok(())
+    async fn write(&self, path: &Path, mut content: &[u8]) -> Result<()> {
+        self.write_atomically(path, &mut content, None).await
     }
 
     async fn read(&self, path: &Path) -> Result<Vec<u8>> {
@@ -28,6 +44,17 @@ impl FileSystem for LocalFileSystem {
         Ok(fs::read(path_buf).await?)
     }
 
+    async fn open_read(&self, path: &Path) -> Result<ReadStream> {
+        let file = fs::File::open(self.abs_path(path)).await?;
+        let size = file.metadata().await?.len();
+        Ok(ReadStream::new(file, size))
+    }
+
+    async fn write_stream(&self, path: &Path, mut stream: ReadStream) -> Result<()> {
+        self.write_atomically(path, &mut stream.reader, stream.updated_at)
+            .await
+    }
+
     async fn mkdir(&self, path: &Path) -> Result<()> {
         let path_buf = self.abs_path(path);
         fs::create_dir_all(path_buf).await?;
@@ -51,11 +78,17 @@ impl FileSystem for LocalFileSystem {
     }
 
     async fn build_tree(&self) -> Result<Node> {
-        let root = self.build_node(&self.root_dir, "".as_ref(), true).await?;
+        let cache = self.load_hash_cache().await;
+        let root = self
+            .build_node(&self.root_dir, "".as_ref(), true, &cache)
+            .await?;
 
         match root.node_type {
             NodeType::File => Err(Error::ExpectDirectory(self.root_dir.clone())),
-            NodeType::Directory => Ok(root),
+            NodeType::Directory => {
+                self.save_hash_cache(&root).await;
+                Ok(root)
+            }
         }
     }
 }
@@ -71,15 +104,95 @@ impl LocalFileSystem {
 
         let local_fs = LocalFileSystem {
             root_dir: absolute_path,
+            full_rehash: false,
         };
         Ok(local_fs)
     }
 
+    pub fn with_full_rehash(mut self, full_rehash: bool) -> Self {
+        self.full_rehash = full_rehash;
+        self
+    }
+
     fn abs_path(&self, relative_path: &Path) -> PathBuf {
         self.root_dir.join(relative_path)
     }
 
-    async fn build_node(&self, abs_path: &Path, parent_path: &Path, is_root: bool) -> Result<Node> {
+    // Write to a temp file in the same directory, fsync it & rename it over path,
+    // so that an interrupted write never leaves a truncated file at path
+    async fn write_atomically(
+        &self,
+        path: &Path,
+        reader: &mut (impl AsyncRead + Unpin + ?Sized),
+        updated_at: Option<DateTime<Utc>>,
+    ) -> Result<()> {
+        let path_buf = self.abs_path(path);
+        let parent = path_buf.parent().unwrap();
+        fs::create_dir_all(parent).await?;
+        let tmp_path = parent.join(format!("{PARTIAL_FILE_PREFIX}{}", Uuid::new_v4()));
+
+        let res = async {
+            let mut file = fs::File::create(&tmp_path).await?;
+            tokio::io::copy(reader, &mut file).await?;
+            file.flush().await?;
+            if let Some(updated_at) = updated_at {
+                let std_file = file.into_std().await;
+                std_file.set_modified(updated_at.into())?;
+                file = fs::File::from_std(std_file);
+            }
+            file.sync_all().await?;
+            fs::rename(&tmp_path, &path_buf).await
+        }
+        .await;
+
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp_path).await;
+        }
+        Ok(res?)
+    }
+
+    async fn load_hash_cache(&self) -> HashCache {
+        if self.full_rehash {
+            return HashCache::new();
+        }
+        // a missing or unreadable cache only means every file gets hashed
+        let tree = fs::read(self.abs_path(HASH_CACHE_FILE.as_ref()))
+            .await
+            .map_err(Error::from)
+            .and_then(|content| Ok(serde_json::from_slice::<Node>(&content)?));
+        match tree {
+            Ok(tree) => tree
+                .into_iter()
+                .filter(|node| node.is_file())
+                .map(|node| (node.path.clone(), node.clone()))
+                .collect(),
+            Err(e) => {
+                debug!("No hash cache for {}: {e}", self.root_dir.display());
+                HashCache::new()
+            }
+        }
+    }
+
+    async fn save_hash_cache(&self, tree: &Node) {
+        let res = match serde_json::to_vec(tree) {
+            Ok(content) => {
+                self.write_atomically(HASH_CACHE_FILE.as_ref(), &mut content.as_slice(), None)
+                    .await
+            }
+            Err(e) => Err(Error::from(e)),
+        };
+        if let Err(e) = res {
+            warn!("Cannot save hash cache of {}: {e}", self.root_dir.display());
+        }
+    }
+
+    async fn build_node(
+        &self,
+        abs_path: &Path,
+        parent_path: &Path,
+        is_root: bool,
+        cache: &HashCache,
+    ) -> Result<Node> {
         let meta = fs::metadata(&abs_path).await?;
         let updated_at = DateTime::from(meta.modified()?);
         let name = String::from(abs_path.file_name().unwrap().to_str().unwrap());
@@ -94,39 +207,40 @@ impl LocalFileSystem {
             let mut children = vec![];
 
             while let Some(entry) = result.next_entry().await? {
-                if is_root && entry.file_name().to_str().unwrap() == CRUSTASYNC_CONFIG_FILE {
+                let file_name = entry.file_name();
+                if is_root
+                    && [CRUSTASYNC_CONFIG_FILE, HASH_CACHE_FILE]
+                        .contains(&file_name.to_str().unwrap())
+                {
                     continue;
                 }
                 let entry_path = entry.path();
-                let node = Box::pin(self.build_node(&entry_path, &path, false)).await?;
+                if entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with(PARTIAL_FILE_PREFIX)
+                {
+                    debug!("Removing partially written {}", entry_path.display());
+                    fs::remove_file(&entry_path).await?;
+                    continue;
+                }
+                let node = Box::pin(self.build_node(&entry_path, &path, false, cache)).await?;
                 children.push(node);
             }
 
-            let mut hasher = Sha256::new();
-
-            children.sort_by_key(|node| node.name.clone().to_lowercase());
-
-            children.iter().for_each(|node| {
-                let filename = node.name.as_bytes();
-                hasher.update(filename);
-                hasher.update(node.content_hash);
-            });
-
-            return Ok(Node {
-                node_type: NodeType::Directory,
-                name,
-                path,
-                updated_at,
-                content_hash: hasher.finalize().into(),
-                children,
-            });
+            return Ok(Node::new_dir(name, path, updated_at, children));
         }
 
-        // TODO read file as stream
-        let content = fs::read(abs_path).await?;
-        let mut hasher = Sha256::new();
-        hasher.update(content);
-        let content_hash = hasher.finalize().into();
+        let size = meta.len();
+        let inode = inode(&meta);
+        // the content is assumed unchanged if size, mtime & inode all match the cached tree
+        let cached = cache.get(&path).filter(|node| {
+            node.size == size && node.updated_at == updated_at && node.inode == inode
+        });
+        let content_hash = match cached {
+            Some(node) => node.content_hash,
+            None => hash_file(abs_path).await?,
+        };
 
         Ok(Node {
             node_type: NodeType::File,
@@ -135,6 +249,34 @@ impl LocalFileSystem {
             updated_at,
             content_hash,
             children: vec![],
+            size,
+            inode,
         })
     }
 }
+
+// Hash the content one buffer at a time, large files are never loaded in memory
+async fn hash_file(abs_path: &Path) -> Result<ContentHash> {
+    let mut reader = BufReader::new(fs::File::open(abs_path).await?);
+    let mut hasher = Sha256::new();
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(hasher.finalize().into());
+        }
+        hasher.update(buf);
+        let len = buf.len();
+        reader.consume(len);
+    }
+}
+
+#[cfg(unix)]
+fn inode(meta: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.ino())
+}
+
+#[cfg(not(unix))]
+fn inode(_meta: &Metadata) -> Option<u64> {
+    None
+}
@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+
+use crate::crustasyncfs::base::{FileSystem, Node, NodeType, CRUSTASYNC_CONFIG_FILE};
+use crate::error::{Error, Result};
+
+#[derive(Debug, Clone)]
+enum Entry {
+    Directory {
+        updated_at: DateTime<Utc>,
+    },
+    File {
+        content: Vec<u8>,
+        updated_at: DateTime<Utc>,
+    },
+}
+
+// Keeps everything in memory, mainly for tests & for embedding crustasync as a library.
+// Entries are keyed by their relative path, the root directory "" always exists
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFileSystem {
+    entries: Arc<RwLock<BTreeMap<PathBuf, Entry>>>,
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn not_found(path: &Path) -> Error {
+        Error::Io(std::io::Error::new(
+            ErrorKind::NotFound,
+            format!("'{}' not found", path.display()),
+        ))
+    }
+
+    // Create all missing ancestors of path, including path itself
+    fn create_dir_all(entries: &mut BTreeMap<PathBuf, Entry>, path: &Path) -> Result<()> {
+        let now = Utc::now();
+        let ancestors = path.ancestors().collect::<Vec<_>>();
+        for dir in ancestors.into_iter().rev().skip(1) {
+            match entries.get(dir) {
+                Some(Entry::Directory { .. }) => {}
+                Some(Entry::File { .. }) => return Err(Error::ExpectDirectory(dir.to_path_buf())),
+                None => {
+                    entries.insert(dir.to_path_buf(), Entry::Directory { updated_at: now });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_dir(entries: &BTreeMap<PathBuf, Entry>, path: &Path) -> bool {
+        path.as_os_str().is_empty() || matches!(entries.get(path), Some(Entry::Directory { .. }))
+    }
+
+    // Paths of the entry and all its descendants
+    fn subtree(entries: &BTreeMap<PathBuf, Entry>, path: &Path) -> Vec<PathBuf> {
+        entries
+            .range(path.to_path_buf()..)
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(path))
+            .cloned()
+            .collect()
+    }
+
+    fn build_node(entries: &BTreeMap<PathBuf, Entry>, path: &Path) -> Node {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        match entries.get(path) {
+            Some(Entry::File {
+                content,
+                updated_at,
+            }) => Node {
+                node_type: NodeType::File,
+                name,
+                path: path.to_path_buf(),
+                updated_at: *updated_at,
+                content_hash: Sha256::digest(content).into(),
+                children: vec![],
+                size: content.len() as u64,
+                inode: None,
+            },
+            dir => {
+                let updated_at = match dir {
+                    Some(Entry::Directory { updated_at }) => *updated_at,
+                    _ => DateTime::UNIX_EPOCH,
+                };
+                let is_root = path.as_os_str().is_empty();
+                let children = entries
+                    .keys()
+                    .filter(|p| p.parent() == Some(path))
+                    .filter(|p| !(is_root && p.as_os_str() == CRUSTASYNC_CONFIG_FILE))
+                    .map(|p| Self::build_node(entries, p))
+                    .collect();
+                Node::new_dir(name, path.to_path_buf(), updated_at, children)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl FileSystem for MemoryFileSystem {
+    async fn write(&self, path: &Path, content: &[u8]) -> Result<()> {
+        let mut entries = self.entries.write().await;
+        if Self::is_dir(&entries, path) {
+            return Err(Error::ExpectFile(path.to_path_buf()));
+        }
+        Self::create_dir_all(&mut entries, path.parent().unwrap())?;
+        entries.insert(
+            path.to_path_buf(),
+            Entry::File {
+                content: content.to_vec(),
+                updated_at: Utc::now(),
+            },
+        );
+        Ok(())
+    }
+
+    async fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let entries = self.entries.read().await;
+        match entries.get(path) {
+            Some(Entry::File { content, .. }) => Ok(content.clone()),
+            Some(Entry::Directory { .. }) => Err(Error::ExpectFile(path.to_path_buf())),
+            None if path.as_os_str().is_empty() => Err(Error::ExpectFile(path.to_path_buf())),
+            None => Err(Self::not_found(path)),
+        }
+    }
+
+    async fn mkdir(&self, path: &Path) -> Result<()> {
+        let mut entries = self.entries.write().await;
+        Self::create_dir_all(&mut entries, path)
+    }
+
+    async fn rm(&self, path: &Path) -> Result<()> {
+        let mut entries = self.entries.write().await;
+        if !entries.contains_key(path) {
+            return Err(Self::not_found(path));
+        }
+        for p in Self::subtree(&entries, path) {
+            entries.remove(&p);
+        }
+        Ok(())
+    }
+
+    // Same semantic as rename(2): an existing file or empty directory at dest is replaced
+    async fn mv(&self, src: &Path, dest: &Path) -> Result<()> {
+        let mut entries = self.entries.write().await;
+        let src_is_dir = match entries.get(src) {
+            Some(entry) => matches!(entry, Entry::Directory { .. }),
+            None => return Err(Self::not_found(src)),
+        };
+        if src.as_os_str().is_empty() || dest.as_os_str().is_empty() {
+            return Err(Error::Io(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "Cannot move the root directory",
+            )));
+        }
+        if dest.starts_with(src) {
+            return Err(Error::Io(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Cannot move '{}' into itself '{}'",
+                    src.display(),
+                    dest.display()
+                ),
+            )));
+        }
+        if !Self::is_dir(&entries, dest.parent().unwrap()) {
+            return Err(Self::not_found(dest.parent().unwrap()));
+        }
+        match entries.get(dest) {
+            Some(Entry::Directory { .. }) if !src_is_dir => {
+                return Err(Error::ExpectFile(dest.to_path_buf()))
+            }
+            Some(Entry::File { .. }) if src_is_dir => {
+                return Err(Error::ExpectDirectory(dest.to_path_buf()))
+            }
+            Some(Entry::Directory { .. }) if Self::subtree(&entries, dest).len() > 1 => {
+                return Err(Error::Io(std::io::Error::new(
+                    ErrorKind::DirectoryNotEmpty,
+                    format!("'{}' is not empty", dest.display()),
+                )))
+            }
+            _ => {}
+        }
+
+        entries.remove(dest);
+        for p in Self::subtree(&entries, src) {
+            let entry = entries.remove(&p).unwrap();
+            let relative_path = p.strip_prefix(src).unwrap();
+            let new_path = if relative_path.as_os_str().is_empty() {
+                dest.to_path_buf()
+            } else {
+                dest.join(relative_path)
+            };
+            entries.insert(new_path, entry);
+        }
+        Ok(())
+    }
+
+    async fn build_tree(&self) -> Result<Node> {
+        let entries = self.entries.read().await;
+        Ok(Self::build_node(&entries, Path::new("")))
+    }
+}
@@ -0,0 +1,389 @@
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use log::{debug, warn};
+use openssh::{KnownHosts, Session, SessionBuilder};
+use openssh_sftp_client::fs::DirEntry;
+use openssh_sftp_client::{Sftp, SftpOptions};
+use sha2::{Digest, Sha256};
+
+use crate::cli::CLIOption;
+use crate::crustasyncfs::base::{
+    ContentHash, FileSystem, Node, NodeType, RequestLimit, CRUSTASYNC_CONFIG_FILE,
+};
+use crate::error::{Error, Result};
+
+// max number of files to pass to a single `sha256sum` invocation
+const REMOTE_HASH_BATCH_SIZE: usize = 64;
+
+// ------------------------------
+// region Error
+// ------------------------------
+
+pub enum SftpError {
+    InvalidLocation { location: String },
+    Ssh(openssh::Error),
+    Sftp(openssh_sftp_client::Error),
+}
+
+impl std::error::Error for SftpError {}
+
+impl Debug for SftpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self, f)
+    }
+}
+
+impl std::fmt::Display for SftpError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SftpError::InvalidLocation { location } => {
+                write!(
+                    f,
+                    "SftpError: Invalid location {location}, expect sftp:user@host:/path"
+                )
+            }
+            SftpError::Ssh(e) => write!(f, "SftpError: SSH error, {e}"),
+            SftpError::Sftp(e) => write!(f, "SftpError: SFTP error, {e}"),
+        }
+    }
+}
+
+impl From<openssh::Error> for SftpError {
+    fn from(value: openssh::Error) -> Self {
+        SftpError::Ssh(value)
+    }
+}
+
+impl From<openssh_sftp_client::Error> for SftpError {
+    fn from(value: openssh_sftp_client::Error) -> Self {
+        SftpError::Sftp(value)
+    }
+}
+
+// endregion
+
+// ------------------------------
+// region FileSystem
+// ------------------------------
+
+#[derive(Debug)]
+pub struct SftpFileSystem {
+    // kept to run remote commands, the sftp channel is multiplexed over the same connection
+    session: Arc<Session>,
+    sftp: Sftp,
+    root_dir: PathBuf,
+    remote_hash: bool,
+    // acquired around each sftp request & remote command, never across several of them
+    requests: RequestLimit,
+}
+
+impl SftpFileSystem {
+    pub async fn new(opt: &CLIOption, location: &str) -> Result<Self> {
+        let (destination, root_dir) = match location.split_once(':') {
+            Some((destination, root_dir)) if !destination.is_empty() => (destination, root_dir),
+            _ => {
+                return Err(Error::from(SftpError::InvalidLocation {
+                    location: location.to_string(),
+                }))
+            }
+        };
+
+        // authentication is delegated to the ssh binary: explicit key file,
+        // then ssh-agent & the usual ~/.ssh config
+        // host keys must be known already, unless new hosts are explicitly trusted
+        let mut builder = SessionBuilder::default();
+        builder.known_hosts_check(if opt.sftp_accept_new_host {
+            warn!("Trusting the host key of {destination} if it is not known yet");
+            KnownHosts::Add
+        } else {
+            KnownHosts::Strict
+        });
+        if let Some(key) = &opt.sftp_key {
+            builder.keyfile(key);
+        }
+        debug!("Connecting to {destination}");
+        let session = Arc::new(builder.connect(destination).await?);
+        let sftp = Sftp::from_clonable_session(session.clone(), SftpOptions::default()).await?;
+
+        // relative paths are relative to the remote home directory
+        let root_dir = if root_dir.is_empty() { "." } else { root_dir };
+        let root_dir = sftp.fs().canonicalize(root_dir).await?;
+        let meta = sftp.fs().metadata(&root_dir).await?;
+        if !meta.file_type().is_some_and(|t| t.is_dir()) {
+            return Err(Error::ExpectDirectory(root_dir));
+        }
+
+        Ok(Self {
+            session,
+            sftp,
+            root_dir,
+            remote_hash: opt.sftp_remote_hash,
+            requests: RequestLimit::new(opt.sftp_concurrency as usize),
+        })
+    }
+
+    fn abs_path(&self, relative_path: &Path) -> PathBuf {
+        self.root_dir.join(relative_path)
+    }
+
+    async fn is_dir(&self, abs_path: &Path) -> Result<bool> {
+        let _permit = self.requests.acquire().await;
+        let meta = self.sftp.fs().metadata(abs_path).await?;
+        Ok(meta.file_type().is_some_and(|t| t.is_dir()))
+    }
+
+    async fn read_dir(&self, abs_path: &Path) -> Result<Vec<DirEntry>> {
+        let _permit = self.requests.acquire().await;
+        Ok(self
+            .sftp
+            .fs()
+            .open_dir(abs_path)
+            .await?
+            .read_dir()
+            .try_collect::<Vec<_>>()
+            .await?)
+    }
+
+    async fn remove_file(&self, abs_path: &Path) -> Result<()> {
+        let _permit = self.requests.acquire().await;
+        Ok(self.sftp.fs().remove_file(abs_path).await?)
+    }
+
+    async fn create_dir_all(&self, abs_path: &Path) -> Result<()> {
+        let missing = abs_path
+            .ancestors()
+            .take_while(|p| p.starts_with(&self.root_dir) && *p != self.root_dir)
+            .collect::<Vec<_>>();
+        for dir in missing.into_iter().rev() {
+            let exists = {
+                let _permit = self.requests.acquire().await;
+                self.sftp.fs().metadata(dir).await.is_ok()
+            };
+            if !exists {
+                debug!("Creating directory {}", dir.display());
+                let _permit = self.requests.acquire().await;
+                self.sftp.fs().create_dir(dir).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn remove_dir_all(&self, abs_path: &Path) -> Result<()> {
+        let entries = self.read_dir(abs_path).await?;
+        for entry in entries {
+            let name = entry.filename();
+            if name == Path::new(".") || name == Path::new("..") {
+                continue;
+            }
+            let child = abs_path.join(name);
+            if entry.file_type().is_some_and(|t| t.is_dir()) {
+                Box::pin(self.remove_dir_all(&child)).await?;
+            } else {
+                self.remove_file(&child).await?;
+            }
+        }
+        let _permit = self.requests.acquire().await;
+        self.sftp.fs().remove_dir(abs_path).await?;
+        Ok(())
+    }
+
+    async fn download_hash(&self, abs_path: &Path) -> Result<ContentHash> {
+        let _permit = self.requests.acquire().await;
+        let content = self.sftp.fs().read(abs_path).await?;
+        Ok(Sha256::digest(&content).into())
+    }
+
+    // Hash files on the remote host, so that their content doesn't have to be downloaded.
+    // Returns None if sha256sum is not available or its output cannot be understood
+    async fn remote_hashes(&self, abs_paths: &[PathBuf]) -> Option<Vec<ContentHash>> {
+        let mut command = self.session.command("sha256sum");
+        command.arg("--");
+        for path in abs_paths {
+            command.arg(path.to_str()?);
+        }
+
+        let output = {
+            let _permit = self.requests.acquire().await;
+            command.output().await
+        };
+        let output = match output {
+            Ok(output) if output.status.success() => output,
+            Ok(output) => {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                warn!("Remote sha256sum failed: {}", stderr.trim());
+                return None;
+            }
+            Err(e) => {
+                warn!("Cannot run remote sha256sum: {e}");
+                return None;
+            }
+        };
+
+        // output lines are in the same order as the arguments: `<hash>  <file name>`,
+        // file names with special characters are escaped and the line starts with a backslash
+        let hashes = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| {
+                let line = line.strip_prefix('\\').unwrap_or(line);
+                hex::decode(line.get(..64)?).ok()?.try_into().ok()
+            })
+            .collect::<Option<Vec<ContentHash>>>()?;
+
+        (hashes.len() == abs_paths.len()).then_some(hashes)
+    }
+
+    async fn content_hashes(&self, abs_paths: &[PathBuf]) -> Result<Vec<ContentHash>> {
+        let mut hashes = Vec::with_capacity(abs_paths.len());
+        for batch in abs_paths.chunks(REMOTE_HASH_BATCH_SIZE) {
+            if self.remote_hash {
+                if let Some(batch_hashes) = self.remote_hashes(batch).await {
+                    hashes.extend(batch_hashes);
+                    continue;
+                }
+                warn!("Falling back to downloading files to compute hashes");
+            }
+            for path in batch {
+                hashes.push(self.download_hash(path).await?);
+            }
+        }
+        Ok(hashes)
+    }
+
+    async fn build_node(
+        &self,
+        abs_path: &Path,
+        path: PathBuf,
+        name: String,
+        updated_at: DateTime<Utc>,
+    ) -> Result<Node> {
+        let entries = self.read_dir(abs_path).await?;
+        let mut children = vec![];
+        let mut files = vec![];
+        for entry in entries {
+            let child_name = entry.filename().to_string_lossy().to_string();
+            if child_name == "." || child_name == ".." {
+                continue;
+            }
+            if path.as_os_str().is_empty() && child_name == CRUSTASYNC_CONFIG_FILE {
+                continue;
+            }
+
+            let child_abs_path = abs_path.join(&child_name);
+            let mut meta = entry.metadata();
+            // follow symlinks, same as the local file system
+            if meta.file_type().is_some_and(|t| t.is_symlink()) {
+                let _permit = self.requests.acquire().await;
+                meta = self.sftp.fs().metadata(&child_abs_path).await?;
+            }
+            let child_updated_at = meta
+                .modified()
+                .map(|t| DateTime::from(t.as_system_time()))
+                .unwrap_or(DateTime::UNIX_EPOCH);
+            let child_path = path.join(&child_name);
+
+            if meta.file_type().is_some_and(|t| t.is_dir()) {
+                let node = Box::pin(self.build_node(
+                    &child_abs_path,
+                    child_path,
+                    child_name,
+                    child_updated_at,
+                ))
+                .await?;
+                children.push(node);
+            } else {
+                let size = meta.len().unwrap_or_default();
+                files.push((
+                    child_abs_path,
+                    child_path,
+                    child_name,
+                    child_updated_at,
+                    size,
+                ));
+            }
+        }
+
+        let abs_paths = files.iter().map(|f| f.0.clone()).collect::<Vec<_>>();
+        let hashes = self.content_hashes(&abs_paths).await?;
+        for ((_, path, name, updated_at, size), content_hash) in files.into_iter().zip(hashes) {
+            children.push(Node {
+                node_type: NodeType::File,
+                name,
+                path,
+                updated_at,
+                content_hash,
+                children: vec![],
+                size,
+                inode: None,
+            });
+        }
+
+        Ok(Node::new_dir(name, path, updated_at, children))
+    }
+}
+
+#[async_trait]
+impl FileSystem for SftpFileSystem {
+    async fn write(&self, path: &Path, content: &[u8]) -> Result<()> {
+        let path_buf = self.abs_path(path);
+        self.create_dir_all(path_buf.parent().unwrap()).await?;
+        debug!("Writing file {}", path_buf.display());
+        let _permit = self.requests.acquire().await;
+        self.sftp.fs().write(path_buf, content).await?;
+        Ok(())
+    }
+
+    async fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let path_buf = self.abs_path(path);
+        debug!("Reading file {}", path_buf.display());
+        let _permit = self.requests.acquire().await;
+        Ok(self.sftp.fs().read(path_buf).await?.to_vec())
+    }
+
+    async fn mkdir(&self, path: &Path) -> Result<()> {
+        self.create_dir_all(&self.abs_path(path)).await
+    }
+
+    async fn rm(&self, path: &Path) -> Result<()> {
+        let path_buf = self.abs_path(path);
+        debug!("Removing {}", path_buf.display());
+        if self.is_dir(&path_buf).await? {
+            self.remove_dir_all(&path_buf).await
+        } else {
+            self.remove_file(&path_buf).await
+        }
+    }
+
+    async fn mv(&self, from: &Path, to: &Path) -> Result<()> {
+        let from = self.abs_path(from);
+        let to = self.abs_path(to);
+        debug!("Moving {} to {}", from.display(), to.display());
+        let _permit = self.requests.acquire().await;
+        self.sftp.fs().rename(from, to).await?;
+        Ok(())
+    }
+
+    async fn build_tree(&self) -> Result<Node> {
+        let meta = {
+            let _permit = self.requests.acquire().await;
+            self.sftp.fs().metadata(&self.root_dir).await?
+        };
+        let updated_at = meta
+            .modified()
+            .map(|t| DateTime::from(t.as_system_time()))
+            .unwrap_or(DateTime::UNIX_EPOCH);
+        let name = self
+            .root_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.build_node(&self.root_dir, PathBuf::from(""), name, updated_at)
+            .await
+    }
+}
+
+// endregion
@@ -0,0 +1,644 @@
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::future::try_join_all;
+use log::debug;
+use percent_encoding::percent_decode_str;
+use quick_xml::events::Event;
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::NsReader;
+use reqwest::{Client as ReqwestClient, Method, RequestBuilder, Response, StatusCode};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::cli::CLIOption;
+use crate::crustasyncfs::base::{
+    ContentHash, FileSystem, Node, NodeType, RequestLimit, CRUSTASYNC_CONFIG_FILE,
+};
+use crate::error::{Error, Result};
+
+const DAV_NS: &[u8] = b"DAV:";
+const OC_NS: &[u8] = b"http://owncloud.org/ns";
+
+// Nextcloud / ownCloud store this header & return it in the oc:checksums property
+const OC_CHECKSUM_HEADER: &str = "OC-Checksum";
+
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <d:prop>
+    <d:resourcetype/>
+    <d:getlastmodified/>
+    <d:getcontentlength/>
+    <oc:checksums/>
+  </d:prop>
+</d:propfind>"#;
+
+// ------------------------------
+// region Error
+// ------------------------------
+
+pub enum DavError {
+    InvalidLocation {
+        location: String,
+    },
+    InvalidData {
+        field: String,
+        message: String,
+    },
+    UnexpectedStatusCode {
+        status_code: StatusCode,
+        message: String,
+    },
+}
+
+impl std::error::Error for DavError {}
+
+impl Debug for DavError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self, f)
+    }
+}
+
+impl std::fmt::Display for DavError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DavError::InvalidLocation { location } => {
+                write!(
+                    f,
+                    "DavError: Invalid location {location}, expect dav:https://host/path"
+                )
+            }
+            DavError::InvalidData { field, message } => {
+                write!(f, "DavError: Invalid data in {field}, {message}")
+            }
+            DavError::UnexpectedStatusCode {
+                status_code,
+                message,
+            } => {
+                write!(
+                    f,
+                    "DavError: Unexpected status code {status_code}, {message}"
+                )
+            }
+        }
+    }
+}
+
+// endregion
+
+// ------------------------------
+// region PROPFIND
+// ------------------------------
+
+// http://www.webdav.org/specs/rfc4918.html#METHOD_PROPFIND
+
+#[derive(Debug, Default)]
+struct DavEntry {
+    href: String,
+    is_collection: bool,
+    last_modified: Option<DateTime<Utc>>,
+    content_length: u64,
+    // space separated list of `<ALGORITHM>:<hex>`, e.g. `SHA1:abc MD5:def`
+    checksums: String,
+}
+
+impl DavEntry {
+    fn sha256(&self) -> Option<ContentHash> {
+        let (_, hash) = self
+            .checksums
+            .split_whitespace()
+            .filter_map(|checksum| checksum.split_once(':'))
+            .find(|(algorithm, _)| algorithm.eq_ignore_ascii_case("SHA256"))?;
+        hex::decode(hash).ok()?.try_into().ok()
+    }
+}
+
+fn parse_multistatus(xml: &str) -> Result<Vec<DavEntry>> {
+    let invalid_data = |e: quick_xml::Error| DavError::InvalidData {
+        field: "multistatus".to_string(),
+        message: e.to_string(),
+    };
+
+    let mut reader = NsReader::from_str(xml);
+    reader.config_mut().trim_text(true);
+
+    let mut entries = vec![];
+    let mut current = DavEntry::default();
+    // (namespace, local name) of the open elements
+    let mut stack: Vec<(Option<&[u8]>, String)> = vec![];
+
+    loop {
+        let (ns, event) = reader.read_resolved_event().map_err(invalid_data)?;
+        let ns = match ns {
+            ResolveResult::Bound(Namespace(DAV_NS)) => Some(DAV_NS),
+            ResolveResult::Bound(Namespace(OC_NS)) => Some(OC_NS),
+            _ => None,
+        };
+
+        match event {
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                if ns == Some(DAV_NS) && name == "response" {
+                    current = DavEntry::default();
+                }
+                if ns == Some(DAV_NS) && name == "collection" {
+                    current.is_collection = true;
+                }
+                stack.push((ns, name));
+            }
+            Event::Empty(e) if ns == Some(DAV_NS) && e.local_name().as_ref() == b"collection" => {
+                current.is_collection = true;
+            }
+            Event::End(_) => {
+                if let Some((Some(DAV_NS), name)) = stack.pop() {
+                    if name == "response" {
+                        entries.push(std::mem::take(&mut current));
+                    }
+                }
+            }
+            Event::Text(e) => {
+                let text = e.unescape().map_err(invalid_data)?;
+                match stack.last() {
+                    Some((Some(DAV_NS), name)) if name == "href" => current.href.push_str(&text),
+                    Some((Some(DAV_NS), name)) if name == "getlastmodified" => {
+                        current.last_modified = DateTime::parse_from_rfc2822(&text)
+                            .map(|date| date.to_utc())
+                            .ok();
+                    }
+                    Some((Some(DAV_NS), name)) if name == "getcontentlength" => {
+                        current.content_length = text.trim().parse().unwrap_or_default();
+                    }
+                    Some((Some(OC_NS), name)) if name == "checksum" => {
+                        current.checksums.push(' ');
+                        current.checksums.push_str(&text);
+                    }
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(entries)
+}
+
+// Decoded names & entries of the children in a depth 1 PROPFIND response of dir_url,
+// which lists the directory itself too. Hrefs are absolute paths or full urls
+fn child_entries(dir_url: &Url, entries: Vec<DavEntry>) -> Result<Vec<(String, DavEntry)>> {
+    let depth = dir_url
+        .path_segments()
+        .unwrap()
+        .filter(|s| !s.is_empty())
+        .count();
+
+    let mut children = vec![];
+    for entry in entries {
+        let href = dir_url
+            .join(&entry.href)
+            .map_err(|e| DavError::InvalidData {
+                field: "href".to_string(),
+                message: e.to_string(),
+            })?;
+        let segments = href
+            .path_segments()
+            .unwrap()
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>();
+        // the directory itself is part of the response
+        if segments.len() <= depth {
+            continue;
+        }
+        let name = percent_decode_str(segments.last().unwrap())
+            .decode_utf8_lossy()
+            .to_string();
+        children.push((name, entry));
+    }
+    Ok(children)
+}
+
+// endregion
+
+// ------------------------------
+// region FileSystem
+// ------------------------------
+
+#[derive(Clone)]
+pub struct WebDavFileSystem {
+    http_client: ReqwestClient,
+    // url of the root directory, without credentials & trailing slash
+    root_url: Url,
+    username: Option<String>,
+    password: Option<String>,
+    requests: RequestLimit,
+}
+
+impl Debug for WebDavFileSystem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebDavFileSystem")
+            .field("root_url", &self.root_url)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WebDavFileSystem {
+    pub async fn new(opt: &CLIOption, location: &str) -> Result<Self> {
+        let invalid_location = || {
+            Error::from(DavError::InvalidLocation {
+                location: location.to_string(),
+            })
+        };
+        let mut root_url = Url::parse(location).map_err(|_| invalid_location())?;
+        if !matches!(root_url.scheme(), "http" | "https") {
+            return Err(invalid_location());
+        }
+
+        // credentials in the url take precedence over the cli options
+        let username = match root_url.username() {
+            "" => opt.dav_username.clone(),
+            username => Some(percent_decode_str(username).decode_utf8_lossy().to_string()),
+        };
+        let password = match root_url.password() {
+            None => opt.dav_password.clone(),
+            Some(password) => Some(percent_decode_str(password).decode_utf8_lossy().to_string()),
+        };
+        root_url.set_username("").map_err(|_| invalid_location())?;
+        root_url
+            .set_password(None)
+            .map_err(|_| invalid_location())?;
+        root_url
+            .path_segments_mut()
+            .map_err(|_| invalid_location())?
+            .pop_if_empty();
+
+        let fs = Self {
+            http_client: ReqwestClient::new(),
+            root_url,
+            username,
+            password,
+            requests: RequestLimit::new(opt.dav_concurrency as usize),
+        };
+
+        let root = fs.propfind(Path::new(""), "0").await?;
+        if !root.first().is_some_and(|entry| entry.is_collection) {
+            return Err(Error::ExpectDirectory(PathBuf::from(location)));
+        }
+        Ok(fs)
+    }
+
+    fn url(&self, path: &Path, is_dir: bool) -> Url {
+        let mut url = self.root_url.clone();
+        {
+            let mut segments = url.path_segments_mut().unwrap();
+            segments.extend(path.iter().map(|s| s.to_string_lossy()));
+            if is_dir {
+                segments.push("");
+            }
+        }
+        url
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        debug!("WebDAV {method} {url}");
+        let builder = self.http_client.request(method, url);
+        match &self.username {
+            Some(username) => builder.basic_auth(username, self.password.as_ref()),
+            None => builder,
+        }
+    }
+
+    // Send a request, waiting for a permit first
+    async fn execute(&self, builder: RequestBuilder) -> Result<Response> {
+        let _permit = self.requests.acquire().await;
+        Ok(builder.send().await?)
+    }
+
+    async fn send(&self, builder: RequestBuilder) -> Result<Response> {
+        let res = self.execute(builder).await?;
+        debug!("Got response status: {}", res.status());
+
+        if !res.status().is_success() {
+            return Err(Error::from(DavError::UnexpectedStatusCode {
+                status_code: res.status(),
+                message: res.text().await.unwrap_or_default(),
+            }));
+        }
+        Ok(res)
+    }
+
+    async fn propfind(&self, path: &Path, depth: &str) -> Result<Vec<DavEntry>> {
+        let builder = self
+            .request(
+                Method::from_bytes(b"PROPFIND").unwrap(),
+                self.url(path, true),
+            )
+            .header("Depth", depth)
+            .header("Content-Type", "application/xml; charset=utf-8")
+            .body(PROPFIND_BODY);
+        let xml = self.send(builder).await?.text().await?;
+        parse_multistatus(&xml)
+    }
+
+    // MKCOL the directory & all its missing ancestors
+    async fn create_dir_all(&self, path: &Path) -> Result<()> {
+        for dir in path
+            .ancestors()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .skip(1)
+        {
+            let builder = self.request(Method::from_bytes(b"MKCOL").unwrap(), self.url(dir, true));
+            let res = self.execute(builder).await?;
+            // 405 Method Not Allowed: already exists
+            if !res.status().is_success() && res.status() != StatusCode::METHOD_NOT_ALLOWED {
+                return Err(Error::from(DavError::UnexpectedStatusCode {
+                    status_code: res.status(),
+                    message: res.text().await.unwrap_or_default(),
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    // Use the checksum stored by Nextcloud if available, otherwise download and hash
+    async fn content_hash(&self, path: &Path, entry: &DavEntry) -> Result<ContentHash> {
+        if let Some(content_hash) = entry.sha256() {
+            return Ok(content_hash);
+        }
+        debug!(
+            "Missing sha256 checksum for {}. Downloading to compute hash",
+            path.display()
+        );
+        let content = self.read(path).await?;
+        Ok(Sha256::digest(content).into())
+    }
+
+    async fn build_node(
+        &self,
+        path: PathBuf,
+        name: String,
+        updated_at: DateTime<Utc>,
+    ) -> Result<Node> {
+        let entries = self.propfind(&path, "1").await?;
+        let mut children = vec![];
+        for (child_name, entry) in child_entries(&self.url(&path, true), entries)? {
+            if path.as_os_str().is_empty() && child_name == CRUSTASYNC_CONFIG_FILE {
+                continue;
+            }
+            children.push((path.join(&child_name), child_name, entry));
+        }
+
+        let children = try_join_all(children.into_iter().map(
+            |(child_path, child_name, entry)| async move {
+                let updated_at = entry.last_modified.unwrap_or(DateTime::UNIX_EPOCH);
+                if entry.is_collection {
+                    return Box::pin(self.build_node(child_path, child_name, updated_at)).await;
+                }
+                let content_hash = self.content_hash(&child_path, &entry).await?;
+                Ok(Node {
+                    node_type: NodeType::File,
+                    name: child_name,
+                    path: child_path,
+                    updated_at,
+                    content_hash,
+                    children: vec![],
+                    size: entry.content_length,
+                    inode: None,
+                })
+            },
+        ))
+        .await?;
+
+        Ok(Node::new_dir(name, path, updated_at, children))
+    }
+}
+
+#[async_trait]
+impl FileSystem for WebDavFileSystem {
+    async fn write(&self, path: &Path, content: &[u8]) -> Result<()> {
+        let checksum = format!("SHA256:{}", hex::encode(Sha256::digest(content)));
+        let put = || {
+            self.request(Method::PUT, self.url(path, false))
+                .header(OC_CHECKSUM_HEADER, &checksum)
+                .body(content.to_vec())
+        };
+
+        // 409 Conflict: parent directory doesn't exist
+        let res = self.execute(put()).await?;
+        if res.status() == StatusCode::CONFLICT {
+            self.create_dir_all(path.parent().unwrap()).await?;
+            self.send(put()).await?;
+            return Ok(());
+        }
+        if !res.status().is_success() {
+            return Err(Error::from(DavError::UnexpectedStatusCode {
+                status_code: res.status(),
+                message: res.text().await.unwrap_or_default(),
+            }));
+        }
+        Ok(())
+    }
+
+    async fn read(&self, path: &Path) -> Result<Vec<u8>> {
+        let builder = self.request(Method::GET, self.url(path, false));
+        Ok(self.send(builder).await?.bytes().await?.into())
+    }
+
+    async fn mkdir(&self, path: &Path) -> Result<()> {
+        self.create_dir_all(path).await
+    }
+
+    async fn rm(&self, path: &Path) -> Result<()> {
+        // DELETE on a collection is always recursive
+        let builder = self.request(Method::DELETE, self.url(path, false));
+        self.send(builder).await?;
+        Ok(())
+    }
+
+    async fn mv(&self, from: &Path, to: &Path) -> Result<()> {
+        let builder = self
+            .request(Method::from_bytes(b"MOVE").unwrap(), self.url(from, false))
+            .header("Destination", self.url(to, false).as_str())
+            .header("Overwrite", "T");
+        self.send(builder).await?;
+        Ok(())
+    }
+
+    async fn build_tree(&self) -> Result<Node> {
+        let root = self.propfind(Path::new(""), "0").await?;
+        let updated_at = root
+            .first()
+            .and_then(|entry| entry.last_modified)
+            .unwrap_or(DateTime::UNIX_EPOCH);
+        let name = self
+            .root_url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .map(|name| percent_decode_str(name).decode_utf8_lossy().to_string())
+            .unwrap_or_default();
+        self.build_node(PathBuf::from(""), name, updated_at).await
+    }
+}
+
+// endregion
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEXTCLOUD_MULTISTATUS: &str = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:oc="http://owncloud.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/files/user/docs/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype><d:collection/></d:resourcetype>
+        <d:getlastmodified>Tue, 02 Jan 2024 10:00:00 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+    <d:propstat>
+      <d:prop>
+        <d:getcontentlength/>
+        <oc:checksums/>
+      </d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/docs/Sub%20Dir/</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype><d:collection/></d:resourcetype>
+        <d:getlastmodified>Wed, 03 Jan 2024 11:30:00 GMT</d:getlastmodified>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/docs/Report%20%231%20%26%20caf%C3%A9.txt</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getlastmodified>Thu, 04 Jan 2024 12:45:30 GMT</d:getlastmodified>
+        <d:getcontentlength>11</d:getcontentlength>
+        <oc:checksums>
+          <oc:checksum>SHA1:2aae6c35c94fcfb415dbe95f408b9ce91ee846ed MD5:5eb63bbbe01eeed093cb22bb8f5acdc3 SHA256:b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9</oc:checksum>
+        </oc:checksums>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/user/docs/no-checksum.bin</d:href>
+    <d:propstat>
+      <d:prop>
+        <d:resourcetype/>
+        <d:getcontentlength>3</d:getcontentlength>
+        <oc:checksums><oc:checksum>SHA1:a9993e364706816aba3e25717850c26c9cd0d89d</oc:checksum></oc:checksums>
+      </d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+    // Apache mod_dav style: default namespace, full urls as href, verbose collection element
+    const APACHE_MULTISTATUS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<multistatus xmlns="DAV:" xmlns:x="urn:example">
+  <response>
+    <href>https://host/dav/docs</href>
+    <propstat><prop><resourcetype><collection></collection></resourcetype></prop></propstat>
+  </response>
+  <response>
+    <href>https://host/dav/docs/a%2Bb.txt</href>
+    <propstat>
+      <prop>
+        <resourcetype/>
+        <getcontentlength>42</getcontentlength>
+        <x:href>not a dav href</x:href>
+      </prop>
+    </propstat>
+  </response>
+</multistatus>"#;
+
+    fn date(rfc3339: &str) -> Option<DateTime<Utc>> {
+        Some(DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc())
+    }
+
+    #[test]
+    fn parses_nextcloud_responses() {
+        let entries = parse_multistatus(NEXTCLOUD_MULTISTATUS).unwrap();
+        assert_eq!(entries.len(), 4);
+
+        assert_eq!(entries[0].href, "/remote.php/dav/files/user/docs/");
+        assert!(entries[0].is_collection);
+        assert_eq!(entries[0].last_modified, date("2024-01-02T10:00:00Z"));
+
+        assert!(entries[1].is_collection);
+        assert_eq!(entries[1].last_modified, date("2024-01-03T11:30:00Z"));
+
+        let file = &entries[2];
+        assert!(!file.is_collection);
+        assert_eq!(file.last_modified, date("2024-01-04T12:45:30Z"));
+        assert_eq!(file.content_length, 11);
+        assert_eq!(
+            file.sha256().map(hex::encode).as_deref(),
+            Some("b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9")
+        );
+
+        // other algorithms only, e.g. files uploaded before checksums were enabled
+        assert_eq!(entries[3].last_modified, None);
+        assert_eq!(entries[3].sha256(), None);
+    }
+
+    #[test]
+    fn parses_default_namespace_responses() {
+        let entries = parse_multistatus(APACHE_MULTISTATUS).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].is_collection);
+        assert_eq!(entries[1].href, "https://host/dav/docs/a%2Bb.txt");
+        assert!(!entries[1].is_collection);
+        assert_eq!(entries[1].content_length, 42);
+        assert_eq!(entries[1].sha256(), None);
+    }
+
+    #[test]
+    fn rejects_mismatched_tags() {
+        assert!(parse_multistatus("<d:multistatus xmlns:d=\"DAV:\"></d:response>").is_err());
+    }
+
+    #[test]
+    fn children_skip_the_directory_itself_and_decode_names() {
+        let dir_url = Url::parse("https://host/remote.php/dav/files/user/docs/").unwrap();
+        let entries = parse_multistatus(NEXTCLOUD_MULTISTATUS).unwrap();
+        let names = child_entries(&dir_url, entries)
+            .unwrap()
+            .into_iter()
+            .map(|(name, entry)| (name, entry.is_collection))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                ("Sub Dir".to_string(), true),
+                ("Report #1 & café.txt".to_string(), false),
+                ("no-checksum.bin".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn children_of_full_url_hrefs() {
+        let dir_url = Url::parse("https://host/dav/docs/").unwrap();
+        let entries = parse_multistatus(APACHE_MULTISTATUS).unwrap();
+        let names = child_entries(&dir_url, entries)
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["a+b.txt".to_string()]);
+    }
+}
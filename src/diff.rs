@@ -1,38 +1,59 @@
 use std::cmp::Reverse;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Debug;
 use std::ops::Add;
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 
-use futures::future::{try_join_all, Future};
-use log::{debug, error, info};
+use chrono::{DateTime, Utc};
+use futures::future::Future;
+use log::{debug, error, info, warn};
 use uuid::Uuid;
 
-use crate::crustasyncfs::base::{ContentHash, FileSystem, Node};
-use crate::error::Result;
+use crate::crustasyncfs::base::{try_join_limited, ContentHash, FileSystem, Node};
+use crate::error::{Error, Result};
 
 #[derive(Clone, Debug)]
 pub enum Task {
-    Move { from: PathBuf, to: PathBuf },
-    Upload { path: PathBuf },
-    CreateDir { path: PathBuf },
-    Delete { path: PathBuf },
-}
-
-impl Node {
-    // Prefix file with ffff, dir with dddd
-    // This is to distinguish between empty files and empty dirs
-    pub fn node_hash(&self) -> ContentHash {
-        let mut hash = self.content_hash;
-        if self.is_file() {
-            hash[0..4].fill(b'f');
-        } else {
-            hash[0..4].fill(b'd');
-        }
-        hash
-    }
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Upload {
+        path: PathBuf,
+        // modification time of the source file
+        updated_at: DateTime<Utc>,
+    },
+    CreateDir {
+        path: PathBuf,
+    },
+    Delete {
+        path: PathBuf,
+    },
+    Conflict {
+        path: PathBuf,
+        resolution: ConflictResolution,
+    },
+}
+
+// How to resolve a path changed differently on both sides in two-way sync
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConflictPolicy {
+    Newest,
+    KeepSource,
+    KeepDestination,
+    KeepBoth,
+    Abort,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConflictResolution {
+    KeepSource,
+    KeepDestination,
+    // the dst file, or the src file if dst is a dir, is kept in both file systems under `copy`
+    KeepBoth { copy: PathBuf },
+    Abort,
 }
 
 // Build a map from node hash to a vector of nodes with that content
@@ -61,8 +82,81 @@ fn build_path_hash_table(tree: &Node) -> HashMap<PathBuf, &Node> {
 // The tasks are divided into priority classes
 // All tasks of the same priority must be completed before processing lower priority tasks
 pub fn build_task_queue(src_tree: &Node, dst_tree: &Node) -> Vec<Vec<Task>> {
+    build_pinned_task_queue(src_tree, dst_tree, &[])
+}
+
+// Dirs of tree holding nodes that were pruned from it, e.g. ignored files
+// Deleting, moving or replacing them would take those nodes along
+pub fn pinned_dirs<'a>(tree: &'a Node, pruned: &Node) -> Vec<&'a Node> {
+    let kept: HashSet<&Path> = pruned.into_iter().map(|n| n.path.as_path()).collect();
+    let table = build_sorted_path_table(tree);
+    let mut pinned = BTreeMap::new();
+    for path in table.keys().filter(|path| !kept.contains(path.as_path())) {
+        // the root is never changed
+        for ancestor in path
+            .ancestors()
+            .skip(1)
+            .filter(|a| !a.as_os_str().is_empty())
+        {
+            pinned.insert(ancestor, table[ancestor]);
+        }
+    }
+    pinned.into_values().collect()
+}
+
+// Add the pinned dirs of the other side to tree, so that syncing keeps them
+// A file at their path cannot be synced without deleting the dir, it is left out
+pub fn pin_dirs(tree: &Node, pinned: &[&Node]) -> Node {
+    let mut table = build_sorted_path_table(tree);
+    pin_in_table(&mut table, pinned);
+    tree_from_path_table(&table)
+}
+
+fn pin_in_table<'a>(table: &mut BTreeMap<PathBuf, &'a Node>, pinned: &[&'a Node]) {
+    for dir in pinned {
+        match table.get(&dir.path) {
+            Some(node) if node.is_dir() => continue,
+            Some(_) => warn!(
+                "Not syncing {:?}, a directory holding ignored or filtered out files is in the way",
+                dir.path
+            ),
+            None => {}
+        }
+        table.insert(dir.path.clone(), dir);
+    }
+}
+
+// Add back to tree the nodes of full_tree that were pruned from it,
+// e.g. to store a synced tree with its ignored or filtered out files
+pub fn restore_pruned(tree: &Node, full_tree: &Node, pruned: &Node) -> Node {
+    let kept: HashSet<&Path> = pruned.into_iter().map(|n| n.path.as_path()).collect();
+    let mut table = build_sorted_path_table(tree);
+    for (path, node) in build_sorted_path_table(full_tree) {
+        if kept.contains(path.as_path()) || table.contains_key(&path) {
+            continue;
+        }
+        let has_parent = path
+            .parent()
+            .and_then(|parent| table.get(parent))
+            .is_some_and(|parent| parent.is_dir());
+        if has_parent {
+            table.insert(path, node);
+        }
+    }
+    tree_from_path_table(&table)
+}
+
+// Same as build_task_queue, except that the pinned dirs of dst_tree are never deleted,
+// moved or replaced, only their children are synced
+pub fn build_pinned_task_queue(
+    src_tree: &Node,
+    dst_tree: &Node,
+    pinned: &[&Node],
+) -> Vec<Vec<Task>> {
     debug!("Start building tasks");
 
+    let src_tree = &pin_dirs(src_tree, pinned);
+    let pinned: HashSet<&Path> = pinned.iter().map(|dir| dir.path.as_path()).collect();
     let empty_path = Path::new("");
 
     // Create new dir must happen before upload & move
@@ -74,134 +168,182 @@ pub fn build_task_queue(src_tree: &Node, dst_tree: &Node) -> Vec<Vec<Task>> {
     let mut queue_4 = vec![]; // upload
                               // queue_5: delete
 
-    let mut src_content_table = build_node_hash_table(src_tree);
-    // let dst_path_table = build_path_hash_table(&dst_tree);
     let src_path_table = build_path_hash_table(src_tree);
+    let dst_content_table = build_node_hash_table(dst_tree);
+
+    // Subtrees that are already the same in src & dst, or will be after queue 3
+    // The root dirs always match each other, they are never moved, created or deleted
+    let mut src_matched = HashSet::new();
+    let mut dst_matched = HashSet::new();
+    // strict ancestors of dst_matched, they can't be moved without moving a matched node too
+    let mut dst_matched_ancestors = HashSet::new();
 
     let mut to_move = HashMap::new();
     let mut to_del = HashMap::new();
 
-    // Move file & directories + delete files
-    debug!("Finding files & dirs to move and delete");
+    // Keep nodes that are the same at the same path
+    // Trees are iterated top down, so the biggest matching subtrees are found first
+    debug!("Finding files & dirs to keep");
     for dst_node in dst_tree {
-        // TODO handle circular rename
-        // src and dst share the same file/directory
-        if let Some(src_nodes) = src_content_table.get_mut(&dst_node.node_hash()) {
-            if !src_nodes.is_empty() {
-                // if any of the src_nodes has the same path -> don't do anything
-                if let Some(idx) = src_nodes.iter().position(|n| n.path == dst_node.path) {
-                    src_nodes.remove(idx);
-                    continue;
-                }
-                // otherwise, move 1 node in the list
-                to_move.insert(
-                    dst_node.path.clone(),
-                    (
-                        Task::Move {
-                            from: dst_node.path.clone(),
-                            to: src_nodes.pop().unwrap().path.clone(),
-                        },
-                        dst_node.is_file(),
-                    ),
-                );
-                continue;
+        if dst_node.path == empty_path || is_under(&dst_node.path, &dst_matched) {
+            continue;
+        }
+        if let Some(src_node) = src_path_table.get(&dst_node.path) {
+            if src_node.node_hash() == dst_node.node_hash() {
+                mark_matched(&dst_node.path, &mut dst_matched, &mut dst_matched_ancestors);
+                src_matched.insert(dst_node.path.clone());
             }
-        };
+        }
+    }
 
-        // content not found in src tree
-        // is file:
-        //      if path in dst fs is a dir: del with highest priority
-        //      else delete later
-        // is dir & is not root dir -> delete
-        if dst_node.is_file() {
-            to_del.insert(
-                dst_node.path.clone(),
-                (
-                    Task::Delete {
-                        path: dst_node.path.clone(),
-                    },
-                    true,
-                ),
-            );
-        } else if dst_node.path != empty_path {
-            // queue_3.push(Task::Delete {
-            //     path: dst_node.path.clone(),
-            // })
-            to_del.insert(
+    // Move files & dirs whose content is found at another path
+    debug!("Finding files & dirs to move");
+    for src_node in src_tree {
+        if src_node.path == empty_path
+            || pinned.contains(src_node.path.as_path())
+            || is_under(&src_node.path, &src_matched)
+        {
+            continue;
+        }
+        let Some(dst_nodes) = dst_content_table.get(&src_node.node_hash()) else {
+            continue;
+        };
+        let dst_node = dst_nodes.iter().find(|n| {
+            n.path != empty_path
+                && !pinned.contains(n.path.as_path())
+                && !is_under(&n.path, &dst_matched)
+                && !dst_matched_ancestors.contains(&n.path)
+        });
+        if let Some(dst_node) = dst_node {
+            mark_matched(&dst_node.path, &mut dst_matched, &mut dst_matched_ancestors);
+            src_matched.insert(src_node.path.clone());
+            to_move.insert(
                 dst_node.path.clone(),
                 (
-                    Task::Delete {
-                        path: dst_node.path.clone(),
+                    Task::Move {
+                        from: dst_node.path.clone(),
+                        to: src_node.path.clone(),
                     },
-                    false,
+                    dst_node.is_file(),
                 ),
             );
         }
     }
 
+    // A node cannot be moved into its own subtree, go through a tmp file
+    let into_itself = to_move
+        .iter()
+        .filter_map(|(from, (task, _))| match task {
+            Task::Move { to, .. } if to.starts_with(from) => Some(from.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    for from in into_itself {
+        move_to_tmp(&from, &mut to_move, &mut queue_0);
+    }
+
+    // Swaps, rotations and chains of renames: a node moves to a path that another node moves away from
+    // Queue 3 runs in parallel, so the nodes moving away go through tmp files first
+    let move_targets = to_move
+        .values()
+        .filter_map(|(task, _)| match task {
+            Task::Move { to, .. } => Some(to.clone()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let occupied = to_move
+        .keys()
+        .filter(|from| move_targets.contains(*from))
+        .cloned()
+        .collect::<Vec<_>>();
+    if !occupied.is_empty() {
+        debug!(
+            "Breaking move cycles & chains through {} tmp file(s)",
+            occupied.len()
+        );
+    }
+    for from in occupied {
+        move_to_tmp(&from, &mut to_move, &mut queue_0);
+    }
+
+    // Delete the remaining files & dirs
+    // A dir is only deleted after its matched descendants are moved out
+    debug!("Finding files & dirs to delete");
+    for dst_node in dst_tree {
+        if dst_node.path == empty_path
+            || pinned.contains(dst_node.path.as_path())
+            || is_under(&dst_node.path, &dst_matched)
+        {
+            continue;
+        }
+        to_del.insert(
+            dst_node.path.clone(),
+            (
+                Task::Delete {
+                    path: dst_node.path.clone(),
+                },
+                dst_node.is_file(),
+            ),
+        );
+    }
+
+    // Moved files & dirs may land on a path whose current node is going to be deleted
+    // Delete it first, a dir cannot be replaced by a rename
+    for to in move_targets {
+        if to_del.remove(&to).is_some() {
+            delete_before_replacing(&to, &mut to_move, &mut queue_0, &mut queue_1);
+        }
+    }
+
     // Create dir & Upload new files
     debug!("Finding new dir to create & new file to write");
-    for new_nodes in src_content_table.values() {
-        for new in new_nodes {
-            if new.is_file() {
-                if let Some((del_task, is_dst_node_file)) = to_del.get(&new.path) {
-                    if *is_dst_node_file {
-                        // dst path is file
-                        // newly uploaded file will override, no need to delete
-                        to_del.remove(&new.path);
-                    } else if let Task::Delete { path } = del_task {
-                        // check if the dir to-be-overwritten have any to-be-moved descendant
-                        // move the descendants to tmp files, then edit their move tasks
-                        //
-                        to_move.iter_mut().for_each(|(k, (task, _))| {
-                            if !k.starts_with(path) {
-                                return;
-                            }
-                            let uuid = Uuid::new_v4().to_string();
-                            let temp_file_name = String::from(".crustasync-").add(&uuid);
-                            let temp_path_buf = PathBuf::from(temp_file_name);
-                            if let Task::Move { from, to: _ } = task {
-                                queue_0.push(Task::Move {
-                                    from: from.clone(),
-                                    to: temp_path_buf.clone(),
-                                });
-                                from.clear();
-                                from.push(temp_path_buf);
-                            }
-                        });
-                        queue_1.push(Task::Delete { path: path.clone() });
-                        to_del.remove(&new.path);
-                    }
-                }
-                queue_4.push(Task::Upload {
-                    path: new.path.clone(),
-                });
-            } else {
-                if let Some((_del_task, is_dst_node_file)) = to_del.get(&new.path) {
-                    // if the path is already a dir, no need to del current one then create new one
-                    if !is_dst_node_file {
-                        to_del.remove(&new.path);
-                        continue;
-                    }
-                    // if the path is file, need to delete it with higher priority
+    let new_nodes = src_tree.into_iter().filter(|n| {
+        n.path != empty_path
+            && !pinned.contains(n.path.as_path())
+            && !is_under(&n.path, &src_matched)
+    });
+    for new in new_nodes {
+        if new.is_file() {
+            if let Some((del_task, is_dst_node_file)) = to_del.get(&new.path) {
+                if *is_dst_node_file {
+                    // dst path is file
+                    // newly uploaded file will override, no need to delete
+                    to_del.remove(&new.path);
+                } else if let Task::Delete { path } = del_task {
+                    delete_before_replacing(path, &mut to_move, &mut queue_0, &mut queue_1);
                     to_del.remove(&new.path);
-                    queue_1.push(Task::Delete {
-                        path: new.path.clone(),
-                    })
                 }
-                if new.path != empty_path {
-                    queue_2.push(Task::CreateDir {
-                        path: new.path.clone(),
-                    });
+            }
+            queue_4.push(Task::Upload {
+                path: new.path.clone(),
+                updated_at: new.updated_at,
+            });
+        } else {
+            if let Some((_del_task, is_dst_node_file)) = to_del.get(&new.path) {
+                // if the path is already a dir, no need to del current one then create new one
+                if !is_dst_node_file {
+                    to_del.remove(&new.path);
+                    continue;
                 }
+                // if the path is file, need to delete it with higher priority
+                to_del.remove(&new.path);
+                queue_1.push(Task::Delete {
+                    path: new.path.clone(),
+                })
             }
+            // the current node at path is moved away in queue 3, too late for creating dir
+            if to_move.contains_key(&new.path) {
+                move_to_tmp(&new.path, &mut to_move, &mut queue_0);
+            }
+            queue_2.push(Task::CreateDir {
+                path: new.path.clone(),
+            });
         }
     }
 
     debug!("Sort and dedup tasks");
 
     // dedup
-    queue_0 = dedup_move_tasks(queue_0);
     queue_1 = dedup_del_tasks(queue_1);
 
     // make sure that parent directories are created first
@@ -214,8 +356,7 @@ pub fn build_task_queue(src_tree: &Node, dst_tree: &Node) -> Vec<Vec<Task>> {
     });
 
     // Put the remaining move task to queue 3
-    let mut queue_3 = to_move.into_iter().map(|(_, (task, _))| task).collect();
-    queue_3 = dedup_move_tasks(queue_3);
+    let queue_3 = to_move.into_iter().map(|(_, (task, _))| task).collect();
 
     // Put the remaining delete task to queue 5
     // Make sure that we don't delete any new files / dirs
@@ -264,34 +405,60 @@ pub fn build_task_queue(src_tree: &Node, dst_tree: &Node) -> Vec<Vec<Task>> {
     result
 }
 
-fn dedup_move_tasks(tasks: Vec<Task>) -> Vec<Task> {
-    let mut from_paths = vec![];
-    let mut to_paths = vec![];
-    for task in &tasks {
-        if let Task::Move { from, to } = task {
-            from_paths.push(from.clone());
-            to_paths.push(to.clone());
-        }
+fn is_under(path: &Path, subtrees: &HashSet<PathBuf>) -> bool {
+    path.ancestors().any(|p| subtrees.contains(p))
+}
+
+fn mark_matched(
+    path: &Path,
+    matched: &mut HashSet<PathBuf>,
+    matched_ancestors: &mut HashSet<PathBuf>,
+) {
+    matched.insert(path.to_path_buf());
+    for ancestor in path.ancestors().skip(1) {
+        matched_ancestors.insert(ancestor.to_path_buf());
     }
+}
 
-    tasks
-        .into_iter()
-        .filter(|task: &Task| {
-            if let Task::Move { from, to } = task {
-                for pb in &from_paths {
-                    if from.starts_with(pb) && from.ne(pb) {
-                        return false;
-                    }
-                }
-                for pb in &to_paths {
-                    if to.starts_with(pb) && to.ne(pb) {
-                        return false;
-                    }
-                }
+// Move the to-be-moved nodes at path or below it to tmp files in queue 0, then edit their move tasks
+// This frees path for a new node before queue 3 runs
+fn move_to_tmp(path: &Path, to_move: &mut HashMap<PathBuf, (Task, bool)>, queue_0: &mut Vec<Task>) {
+    to_move.iter_mut().for_each(|(k, (task, _))| {
+        if !k.starts_with(path) {
+            return;
+        }
+        let uuid = Uuid::new_v4().to_string();
+        let temp_file_name = String::from(".crustasync-").add(&uuid);
+        let temp_path_buf = PathBuf::from(temp_file_name);
+        // skip if already moved to a tmp file
+        if let Task::Move { from, to: _ } = task.clone() {
+            if from != *k {
+                return;
             }
-            true
-        })
-        .collect()
+        }
+        if let Task::Move { from, to: _ } = task {
+            queue_0.push(Task::Move {
+                from: from.clone(),
+                to: temp_path_buf.clone(),
+            });
+            from.clear();
+            from.push(temp_path_buf);
+        }
+    });
+}
+
+// Delete path in queue 1 so that it can be replaced by a new node
+// Its to-be-moved descendants are moved to tmp files first
+fn delete_before_replacing(
+    path: &Path,
+    to_move: &mut HashMap<PathBuf, (Task, bool)>,
+    queue_0: &mut Vec<Task>,
+    queue_1: &mut Vec<Task>,
+) {
+    move_to_tmp(path, to_move, queue_0);
+    queue_1.push(Task::Delete {
+        path: path.to_path_buf(),
+    });
 }
 
 fn dedup_del_tasks(tasks: Vec<Task>) -> Vec<Task> {
@@ -321,6 +488,305 @@ fn dedup_del_tasks(tasks: Vec<Task>) -> Vec<Task> {
         .collect()
 }
 
+// Result of planning a two-way sync
+// src_queues read content from dst and vice versa, run them with process_two_way_tasks
+#[derive(Debug)]
+pub struct TwoWayPlan {
+    pub src_queues: Vec<Vec<Task>>,
+    pub dst_queues: Vec<Vec<Task>>,
+    // common ancestor for the next run, to be written to both file systems
+    pub base_tree: Node,
+    // paths changed differently on both sides
+    pub conflicts: Vec<PathBuf>,
+}
+
+// Two nodes are considered the same entry if they are both dirs or both files with same content
+// Dir content is compared through its children, so dirs never differ by themselves
+fn same_entry(a: Option<&Node>, b: Option<&Node>) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => match (a.is_dir(), b.is_dir()) {
+            (true, true) => true,
+            (false, false) => a.content_hash == b.content_hash,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn build_sorted_path_table(tree: &Node) -> BTreeMap<PathBuf, &Node> {
+    tree.into_iter()
+        .map(|node| (node.path.clone(), node))
+        .collect()
+}
+
+// Make sure every node in the table has a directory as parent
+//  - parent is a file -> the child is dropped
+//  - parent is missing -> the parent is re-created from any tree that has it as a dir
+fn fix_orphans<'a>(
+    table: &mut BTreeMap<PathBuf, &'a Node>,
+    trees: &[&BTreeMap<PathBuf, &'a Node>],
+) {
+    let paths: Vec<PathBuf> = table.keys().cloned().collect();
+    for path in paths {
+        if !table.contains_key(&path) {
+            continue;
+        }
+        let mut ancestors: Vec<&Path> = path.ancestors().skip(1).collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            match table.get(ancestor) {
+                Some(node) if node.is_dir() => continue,
+                Some(_) => {
+                    table.remove(&path);
+                    break;
+                }
+                None => {
+                    // every path comes from one of the trees, or is a sibling of such a path,
+                    // so one of them has its ancestors as dirs
+                    let dir = trees
+                        .iter()
+                        .find_map(|t| t.get(ancestor).filter(|n| n.is_dir()))
+                        .expect("ancestor is a dir in one of the trees");
+                    table.insert(ancestor.to_path_buf(), dir);
+                }
+            }
+        }
+    }
+}
+
+fn resolve_conflict(
+    policy: ConflictPolicy,
+    src: Option<&Node>,
+    dst: Option<&Node>,
+    taken: &[&BTreeMap<PathBuf, &Node>],
+) -> ConflictResolution {
+    match policy {
+        ConflictPolicy::KeepSource => ConflictResolution::KeepSource,
+        ConflictPolicy::KeepDestination => ConflictResolution::KeepDestination,
+        ConflictPolicy::Abort => ConflictResolution::Abort,
+        // a deleted side has no time, the side that still has the path wins
+        ConflictPolicy::Newest => match (src, dst) {
+            (Some(src), Some(dst)) if dst.updated_at > src.updated_at => {
+                ConflictResolution::KeepDestination
+            }
+            (None, Some(_)) => ConflictResolution::KeepDestination,
+            _ => ConflictResolution::KeepSource,
+        },
+        ConflictPolicy::KeepBoth => match (src, dst) {
+            (Some(src), Some(_)) => ConflictResolution::KeepBoth {
+                copy: conflict_copy_path(&src.path, taken),
+            },
+            (None, Some(_)) => ConflictResolution::KeepDestination,
+            _ => ConflictResolution::KeepSource,
+        },
+    }
+}
+
+// Find a free sibling path for a conflicting file: `name (conflict copy).ext`
+fn conflict_copy_path(path: &Path, taken: &[&BTreeMap<PathBuf, &Node>]) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path
+        .extension()
+        .map(|e| format!(".{}", e.to_string_lossy()))
+        .unwrap_or_default();
+    let mut counter = 1;
+    loop {
+        let suffix = if counter == 1 {
+            String::from("conflict copy")
+        } else {
+            format!("conflict copy {counter}")
+        };
+        let copy = path.with_file_name(format!("{stem} ({suffix}){ext}"));
+        if !taken.iter().any(|t| t.contains_key(&copy)) {
+            return copy;
+        }
+        counter += 1;
+    }
+}
+
+// Build a Node tree from a flat path table, re-computing directory hashes
+fn tree_from_path_table(table: &BTreeMap<PathBuf, &Node>) -> Node {
+    let mut children_table: HashMap<&Path, Vec<&Node>> = HashMap::new();
+    for (path, node) in table {
+        if let Some(parent) = path.parent() {
+            children_table.entry(parent).or_default().push(node);
+        }
+    }
+
+    fn build(node: &Node, children_table: &HashMap<&Path, Vec<&Node>>) -> Node {
+        if node.is_file() {
+            return Node {
+                children: vec![],
+                ..node.clone()
+            };
+        }
+        let children = children_table
+            .get(node.path.as_path())
+            .map(|nodes| nodes.iter().map(|n| build(n, children_table)).collect())
+            .unwrap_or_default();
+        Node::new_dir(
+            node.name.clone(),
+            node.path.clone(),
+            node.updated_at,
+            children,
+        )
+    }
+
+    build(table.get(Path::new("")).unwrap(), &children_table)
+}
+
+// Keep the pinned dirs of one side whatever the other side did to them
+// The other side gets them back, unless it ends up with a file there
+fn pin_in_targets<'a>(
+    target: &mut BTreeMap<PathBuf, &'a Node>,
+    other: &mut BTreeMap<PathBuf, &'a Node>,
+    pinned: &[&'a Node],
+) {
+    pin_in_table(target, pinned);
+    for dir in pinned {
+        other.entry(dir.path.clone()).or_insert(dir);
+    }
+}
+
+// Three-way merge src_tree and dst_tree, using base_tree as their common ancestor
+// For each path:
+//      changed on one side only -> the change is applied to the other side
+//      changed on both sides the same way -> nothing to do
+//      changed on both sides differently -> conflict, resolved according to policy
+// The pinned dirs of each side are never deleted, moved or replaced on that side
+pub fn build_two_way_task_queue(
+    base_tree: &Node,
+    src_tree: &Node,
+    dst_tree: &Node,
+    policy: ConflictPolicy,
+    src_pinned: &[&Node],
+    dst_pinned: &[&Node],
+) -> TwoWayPlan {
+    debug!("Start building two-way tasks");
+
+    let base_table = build_sorted_path_table(base_tree);
+    let src_table = build_sorted_path_table(src_tree);
+    let dst_table = build_sorted_path_table(dst_tree);
+
+    let mut all_paths: Vec<&PathBuf> = base_table
+        .keys()
+        .chain(src_table.keys())
+        .chain(dst_table.keys())
+        .collect();
+    all_paths.sort();
+    all_paths.dedup();
+
+    let mut src_target = BTreeMap::new();
+    let mut dst_target = BTreeMap::new();
+    let mut conflict_tasks = vec![];
+    let mut copies = vec![];
+
+    for path in all_paths {
+        let base = base_table.get(path).copied();
+        let src = src_table.get(path).copied();
+        let dst = dst_table.get(path).copied();
+
+        let src_changed = !same_entry(base, src);
+        let dst_changed = !same_entry(base, dst);
+
+        let (src_node, dst_node) = match (src_changed, dst_changed) {
+            (true, false) => (src, src),
+            (false, true) => (dst, dst),
+            (true, true) if !same_entry(src, dst) => {
+                let resolution = resolve_conflict(policy, src, dst, &[&src_table, &dst_table]);
+                debug!("Conflict at {:?}, resolution {:?}", path, resolution);
+                let nodes = match &resolution {
+                    ConflictResolution::KeepSource => (src, src),
+                    ConflictResolution::KeepDestination => (dst, dst),
+                    ConflictResolution::Abort => (src, dst),
+                    ConflictResolution::KeepBoth { copy } => {
+                        // dst is renamed, unless it is a dir, in which case src must be a file
+                        let (kept, renamed) = match (src, dst) {
+                            (Some(src), Some(dst)) if dst.is_dir() => (dst, src),
+                            (Some(src), Some(dst)) => (src, dst),
+                            _ => unreachable!(),
+                        };
+                        copies.push(Node {
+                            name: copy.file_name().unwrap().to_string_lossy().to_string(),
+                            path: copy.clone(),
+                            ..renamed.clone()
+                        });
+                        (Some(kept), Some(kept))
+                    }
+                };
+                conflict_tasks.push(Task::Conflict {
+                    path: path.clone(),
+                    resolution,
+                });
+                nodes
+            }
+            _ => (src, dst),
+        };
+        if let Some(node) = src_node {
+            src_target.insert(path.clone(), node);
+        }
+        if let Some(node) = dst_node {
+            dst_target.insert(path.clone(), node);
+        }
+    }
+
+    for copy in &copies {
+        src_target.insert(copy.path.clone(), copy);
+        dst_target.insert(copy.path.clone(), copy);
+    }
+
+    // roots are never changed
+    src_target.insert(PathBuf::from(""), src_tree);
+    dst_target.insert(PathBuf::from(""), dst_tree);
+
+    let trees = [&src_table, &dst_table, &base_table];
+    fix_orphans(&mut src_target, &trees);
+    fix_orphans(&mut dst_target, &trees);
+    pin_in_targets(&mut src_target, &mut dst_target, src_pinned);
+    pin_in_targets(&mut dst_target, &mut src_target, dst_pinned);
+
+    // New common ancestor: paths that end up the same on both sides
+    let mut base_target: BTreeMap<PathBuf, &Node> = src_target
+        .iter()
+        .filter(|(path, node)| same_entry(Some(node), dst_target.get(*path).copied()))
+        .map(|(path, node)| (path.clone(), *node))
+        .collect();
+    let paths: Vec<PathBuf> = base_target.keys().cloned().collect();
+    for path in paths {
+        let has_parent = path
+            .parent()
+            .is_none_or(|parent| base_target.contains_key(parent));
+        if !has_parent {
+            base_target.remove(&path);
+        }
+    }
+
+    let src_target_tree = tree_from_path_table(&src_target);
+    let dst_target_tree = tree_from_path_table(&dst_target);
+
+    let mut src_queues = build_pinned_task_queue(&src_target_tree, src_tree, src_pinned);
+    let mut dst_queues = build_pinned_task_queue(&dst_target_tree, dst_tree, dst_pinned);
+    src_queues[0].extend(conflict_tasks.iter().cloned());
+    dst_queues[0].extend(conflict_tasks.iter().cloned());
+
+    let conflicts: Vec<PathBuf> = conflict_tasks
+        .into_iter()
+        .filter_map(|task| match task {
+            Task::Conflict { path, .. } => Some(path),
+            _ => None,
+        })
+        .collect();
+    debug!("Build two-way tasks done. {} conflict(s)", conflicts.len());
+
+    TwoWayPlan {
+        src_queues,
+        dst_queues,
+        base_tree: tree_from_path_table(&base_target),
+        conflicts,
+    }
+}
+
 async fn process_move(fs: Arc<dyn FileSystem>, from: &Path, to: &Path) -> Result<()> {
     info!("Start moving from {:?} to {:?}", from, to);
     let res = fs.mv(from, to).await;
@@ -336,10 +802,12 @@ async fn process_upload(
     src_fs: Arc<dyn FileSystem>,
     dst_fs: Arc<dyn FileSystem>,
     path: &Path,
+    updated_at: &DateTime<Utc>,
 ) -> Result<()> {
     info!("Start uploading to {:?}", path);
-    let content = src_fs.read(path).await?;
-    let res = dst_fs.write(path, &content).await;
+    let mut stream = src_fs.open_read(path).await?;
+    stream.updated_at = Some(*updated_at);
+    let res = dst_fs.write_stream(path, stream).await;
 
     if res.is_err() {
         error!("Error uploading to {:?}", path);
@@ -371,25 +839,75 @@ async fn process_delete(fs: Arc<dyn FileSystem>, path: &Path) -> Result<()> {
     res
 }
 
+async fn process_conflict(path: &Path, resolution: &ConflictResolution) -> Result<()> {
+    if *resolution == ConflictResolution::Abort {
+        error!("Conflict at {:?}. Aborting", path);
+        return Err(Error::Conflict(path.to_path_buf()));
+    }
+    warn!("Conflict at {:?} resolved by {:?}", path, resolution);
+    Ok(())
+}
+
+async fn process_queue(
+    src_fs: Arc<dyn FileSystem>,
+    dst_fs: Arc<dyn FileSystem>,
+    queue: &[Task],
+    concurrency: usize,
+) -> Result<()> {
+    let futures = queue.iter().map(|task: &Task| {
+        let dst_fs = dst_fs.clone();
+        let box_future: Pin<Box<dyn Future<Output = Result<()>>>> = match task {
+            Task::Move { from, to } => Box::pin(process_move(dst_fs, from, to)),
+            Task::Upload { path, updated_at } => {
+                Box::pin(process_upload(src_fs.clone(), dst_fs, path, updated_at))
+            }
+            Task::CreateDir { path } => Box::pin(process_create_dir(dst_fs, path)),
+            Task::Delete { path } => Box::pin(process_delete(dst_fs, path)),
+            Task::Conflict { path, resolution } => Box::pin(process_conflict(path, resolution)),
+        };
+        box_future
+    });
+    try_join_limited(futures, concurrency).await?;
+    Ok(())
+}
+
 pub async fn process_tasks(
     src_fs: Arc<dyn FileSystem>,
     dst_fs: Arc<dyn FileSystem>,
     queues: &Vec<Vec<Task>>,
+    concurrency: usize,
 ) -> Result<()> {
     info!("Start processing tasks");
     for queue in queues {
-        let futures = queue.iter().map(|task: &Task| {
-            let dst_fs = dst_fs.clone();
-            let box_future: Pin<Box<dyn Future<Output = Result<()>>>> = match task {
-                Task::Move { from, to } => Box::pin(process_move(dst_fs, from, to)),
-                Task::Upload { path } => Box::pin(process_upload(src_fs.clone(), dst_fs, path)),
-                Task::CreateDir { path } => Box::pin(process_create_dir(dst_fs, path)),
-                Task::Delete { path } => Box::pin(process_delete(dst_fs, path)),
-            };
-            box_future
-        });
-        try_join_all(futures).await?;
+        process_queue(src_fs.clone(), dst_fs.clone(), queue, concurrency).await?;
     }
     info!("Processing tasks done");
     Ok(())
 }
+
+// Run the tasks of a two-way plan
+// Both sides are processed one priority class at a time, dst first then src
+// so that a file renamed on one side exists before the other side uploads it
+pub async fn process_two_way_tasks(
+    src_fs: Arc<dyn FileSystem>,
+    dst_fs: Arc<dyn FileSystem>,
+    plan: &TwoWayPlan,
+    concurrency: usize,
+) -> Result<()> {
+    // abort before touching any file system
+    for task in &plan.dst_queues[0] {
+        if let Task::Conflict { path, resolution } = task {
+            if *resolution == ConflictResolution::Abort {
+                return Err(Error::Conflict(path.clone()));
+            }
+        }
+    }
+
+    info!("Start processing two-way tasks");
+    for (src_queue, dst_queue) in plan.src_queues.iter().zip(&plan.dst_queues) {
+        process_queue(src_fs.clone(), dst_fs.clone(), dst_queue, concurrency).await?;
+        process_queue(dst_fs.clone(), src_fs.clone(), src_queue, concurrency).await?;
+    }
+    info!("Processing two-way tasks done");
+    Ok(())
+}
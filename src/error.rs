@@ -3,11 +3,15 @@ use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
 use crate::crustasyncfs::googledrive::GDError;
+use crate::crustasyncfs::s3::S3Error;
+use crate::crustasyncfs::sftp::SftpError;
+use crate::crustasyncfs::webdav::DavError;
 
 pub enum Error {
     // Generic errors
     ExpectDirectory(PathBuf),
     ExpectFile(PathBuf),
+    Conflict(PathBuf),
     Serde(serde_json::Error),
     Utf8(FromUtf8Error),
     Request(reqwest::Error),
@@ -16,6 +20,9 @@ pub enum Error {
 
     // module specific errors
     GoogleDrive(GDError),
+    S3(S3Error),
+    Sftp(SftpError),
+    WebDav(DavError),
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -46,6 +53,13 @@ impl Display for Error {
                     path.display()
                 )
             }
+            Error::Conflict(path) => {
+                write!(
+                    f,
+                    "Conflict: '{}' was changed on both sides",
+                    path.display()
+                )
+            }
             Error::Serde(e) => std::fmt::Display::fmt(&e, f),
             Error::Utf8(e) => std::fmt::Display::fmt(&e, f),
             Error::Request(e) => std::fmt::Display::fmt(&e, f),
@@ -53,6 +67,9 @@ impl Display for Error {
             Error::Unknown(e) => write!(f, "UnknownError: {e}"),
             // module specific errors
             Error::GoogleDrive(e) => std::fmt::Display::fmt(&e, f),
+            Error::S3(e) => std::fmt::Display::fmt(&e, f),
+            Error::Sftp(e) => std::fmt::Display::fmt(&e, f),
+            Error::WebDav(e) => std::fmt::Display::fmt(&e, f),
         }
     }
 }
@@ -97,4 +114,34 @@ impl From<GDError> for Error {
     }
 }
 
+impl From<S3Error> for Error {
+    fn from(value: S3Error) -> Self {
+        Error::S3(value)
+    }
+}
+
+impl From<SftpError> for Error {
+    fn from(value: SftpError) -> Self {
+        Error::Sftp(value)
+    }
+}
+
+impl From<openssh::Error> for Error {
+    fn from(value: openssh::Error) -> Self {
+        Error::Sftp(SftpError::from(value))
+    }
+}
+
+impl From<openssh_sftp_client::Error> for Error {
+    fn from(value: openssh_sftp_client::Error) -> Self {
+        Error::Sftp(SftpError::from(value))
+    }
+}
+
+impl From<DavError> for Error {
+    fn from(value: DavError) -> Self {
+        Error::WebDav(value)
+    }
+}
+
 // endregion
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use crate::cli::CLIOption;
+use crate::crustasyncfs::base::Node;
+use crate::error::{Error, Result};
+
+// Filters given on the command line to sync a slice of a tree.
+// Globs match paths relative to the root, the other filters only apply to files
+#[derive(Debug, Clone)]
+pub struct TreeFilter {
+    // None to include every file
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<DateTime<Utc>>,
+}
+
+impl TreeFilter {
+    pub fn new(opt: &CLIOption) -> Result<Self> {
+        let include = if opt.include.is_empty() {
+            None
+        } else {
+            Some(Self::glob_set(&opt.include)?)
+        };
+        Ok(Self {
+            include,
+            exclude: Self::glob_set(&opt.exclude)?,
+            min_size: opt.min_size,
+            max_size: opt.max_size,
+            newer_than: opt.newer_than,
+        })
+    }
+
+    fn glob_set(globs: &[Glob]) -> Result<GlobSet> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            builder.add(glob.clone());
+        }
+        builder
+            .build()
+            .map_err(|e| Error::Unknown(anyhow!("Invalid glob: {e}")))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_none()
+            && self.exclude.is_empty()
+            && self.min_size.is_none()
+            && self.max_size.is_none()
+            && self.newer_than.is_none()
+    }
+
+    fn keeps_file(&self, node: &Node) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(&node.path))
+            && self.min_size.is_none_or(|min_size| node.size >= min_size)
+            && self.max_size.is_none_or(|max_size| node.size <= max_size)
+            && self
+                .newer_than
+                .is_none_or(|newer_than| node.updated_at >= newer_than)
+    }
+
+    // Copy of the source tree without the filtered out nodes
+    pub fn prune_src(&self, src_tree: &Node) -> Node {
+        self.prune(src_tree, &|node| self.keeps_file(node))
+    }
+
+    // Copy of a tree synced with the source tree, e.g. the destination tree.
+    // Where the source has a file, its file is kept if the source file is, otherwise
+    // an old or big version on one side would be deleted instead of updated or left alone
+    pub fn prune_other(&self, src_tree: &Node, tree: &Node) -> Node {
+        let src_files = src_tree
+            .into_iter()
+            .filter(|node| node.is_file())
+            .map(|node| (node.path.as_path(), node))
+            .collect::<HashMap<&Path, &Node>>();
+        self.prune(tree, &|node| match src_files.get(node.path.as_path()) {
+            Some(src_node) => self.keeps_file(src_node),
+            None => self.keeps_file(node),
+        })
+    }
+
+    // Directories left empty by the filters are removed too,
+    // directories empty already are kept unless only some files are included
+    fn prune(&self, tree: &Node, keep_file: &dyn Fn(&Node) -> bool) -> Node {
+        let children = tree
+            .children
+            .iter()
+            .filter(|child| !self.exclude.is_match(&child.path))
+            .filter_map(|child| {
+                if child.is_file() {
+                    return keep_file(child).then(|| child.clone());
+                }
+                let pruned = self.prune(child, keep_file);
+                let was_empty = child.children.is_empty() && self.include.is_none();
+                (!pruned.children.is_empty() || was_empty).then_some(pruned)
+            })
+            .collect();
+        Node::new_dir(
+            tree.name.clone(),
+            tree.path.clone(),
+            tree.updated_at,
+            children,
+        )
+    }
+}
@@ -4,5 +4,6 @@ pub mod cli;
 pub mod crustasyncfs;
 pub mod diff;
 pub mod error;
+pub mod filter;
 pub mod oauth;
 pub mod utils;
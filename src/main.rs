@@ -1,10 +1,20 @@
 #![recursion_limit = "256"]
 
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::Utc;
 use clap::Parser;
-use crustasync::cli::LogLevel;
+use crustasync::cli::{LogLevel, SyncMode};
+use crustasync::crustasyncfs::base::{FileSystem, IgnoreFilter, Node};
 use crustasync::crustasyncfs::fs_from_location_str;
-use crustasync::diff::{build_task_queue, process_tasks};
+use crustasync::diff::{
+    build_pinned_task_queue, build_two_way_task_queue, pin_dirs, pinned_dirs, process_tasks,
+    process_two_way_tasks, restore_pruned,
+};
+use crustasync::filter::TreeFilter;
 use crustasync::{cli, utils};
+use log::{info, warn};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -21,10 +31,28 @@ async fn main() -> anyhow::Result<()> {
     let src_fs = fs_from_location_str(&option.src_dir, &option).await?;
     let dest_fs = fs_from_location_str(&option.dst_dir, &option).await?;
 
+    if option.mode == SyncMode::TwoWay {
+        return two_way_sync(&option, src_fs, dest_fs).await;
+    }
+
     let src_tree = src_fs.get_tree(true).await?;
-    let dest_tree = dest_fs.get_tree(true).await?;
+    let full_dest_tree = dest_fs.get_tree(true).await?;
+    let filters = ignore_filters(&*src_fs, &src_tree, &*dest_fs, &full_dest_tree).await?;
+    let src_tree = prune(&filters, &src_tree);
+    let dest_tree = prune(&filters, &full_dest_tree);
+
+    let tree_filter = TreeFilter::new(&option)?;
+    let (src_tree, dest_tree) = if tree_filter.is_empty() {
+        (src_tree, dest_tree)
+    } else {
+        let dest_tree = tree_filter.prune_other(&src_tree, &dest_tree);
+        (tree_filter.prune_src(&src_tree), dest_tree)
+    };
 
-    let queues = build_task_queue(&src_tree, &dest_tree);
+    // Dest dirs holding ignored or filtered out files are kept
+    let pinned = pinned_dirs(&full_dest_tree, &dest_tree);
+    let src_tree = pin_dirs(&src_tree, &pinned);
+    let queues = build_pinned_task_queue(&src_tree, &dest_tree, &pinned);
 
     if option.log_level <= LogLevel::INFO || option.dry_run {
         println!("\n\nSOURCE TREE:\n");
@@ -37,9 +65,120 @@ async fn main() -> anyhow::Result<()> {
     }
 
     if !option.dry_run {
-        process_tasks(src_fs, dest_fs.clone(), &queues).await?;
-        dest_fs.write_tree_to_file(&src_tree).await?;
+        process_tasks(
+            src_fs,
+            dest_fs.clone(),
+            &queues,
+            option.concurrency as usize,
+        )
+        .await?;
+        let synced_tree = restore_pruned(&src_tree, &full_dest_tree, &dest_tree);
+        dest_fs.write_tree_to_file(&synced_tree).await?;
     }
 
     Ok(())
 }
+
+async fn two_way_sync(
+    option: &cli::CLIOption,
+    src_fs: Arc<dyn FileSystem + Send + Sync>,
+    dest_fs: Arc<dyn FileSystem + Send + Sync>,
+) -> anyhow::Result<()> {
+    // The stored tree must be read before building the current trees
+    // because get_tree overwrites it
+    let full_base_tree = match dest_fs.read_tree_from_file().await {
+        Ok(tree) => tree,
+        Err(_) => match src_fs.read_tree_from_file().await {
+            Ok(tree) => tree,
+            Err(_) => {
+                info!("Cannot find previous sync tree. Treating every file as new");
+                Node::new_dir(String::new(), PathBuf::from(""), Utc::now(), vec![])
+            }
+        },
+    };
+
+    let full_src_tree = src_fs.build_tree().await?;
+    let full_dest_tree = dest_fs.build_tree().await?;
+    let filters = ignore_filters(&*src_fs, &full_src_tree, &*dest_fs, &full_dest_tree).await?;
+    let base_tree = prune(&filters, &full_base_tree);
+    let src_tree = prune(&filters, &full_src_tree);
+    let dest_tree = prune(&filters, &full_dest_tree);
+
+    let tree_filter = TreeFilter::new(option)?;
+    let (base_tree, src_tree, dest_tree) = if tree_filter.is_empty() {
+        (base_tree, src_tree, dest_tree)
+    } else {
+        (
+            tree_filter.prune_other(&src_tree, &base_tree),
+            tree_filter.prune_src(&src_tree),
+            tree_filter.prune_other(&src_tree, &dest_tree),
+        )
+    };
+
+    // Dirs holding ignored or filtered out files are kept
+    let src_pinned = pinned_dirs(&full_src_tree, &src_tree);
+    let dest_pinned = pinned_dirs(&full_dest_tree, &dest_tree);
+
+    let plan = build_two_way_task_queue(
+        &base_tree,
+        &src_tree,
+        &dest_tree,
+        option.conflict_policy.policy(),
+        &src_pinned,
+        &dest_pinned,
+    );
+
+    if !plan.conflicts.is_empty() {
+        warn!("Found {} conflict(s)", plan.conflicts.len());
+    }
+
+    if option.log_level <= LogLevel::INFO || option.dry_run {
+        println!("\n\nBASE TREE:\n");
+        utils::print_tree(&base_tree);
+        println!("\n\nSOURCE TREE:\n");
+        utils::print_tree(&src_tree);
+        println!("\n\nDEST TREE:\n");
+        utils::print_tree(&dest_tree);
+        println!("\n\nSOURCE TASK QUEUES:\n");
+        utils::print_task_queues(&plan.src_queues);
+        println!("\n\nDEST TASK QUEUES:\n");
+        utils::print_task_queues(&plan.dst_queues);
+        println!("\n\n");
+    }
+
+    if !option.dry_run {
+        process_two_way_tasks(
+            src_fs.clone(),
+            dest_fs.clone(),
+            &plan,
+            option.concurrency as usize,
+        )
+        .await?;
+        // paths left out of this sync keep their previous common ancestor
+        let base_tree = restore_pruned(&plan.base_tree, &full_base_tree, &base_tree);
+        src_fs.write_tree_to_file(&base_tree).await?;
+        dest_fs.write_tree_to_file(&base_tree).await?;
+    }
+
+    Ok(())
+}
+
+// The rules of both sides apply to every tree, so that a path ignored on either side
+// is never deleted or overwritten, even before its .crustasyncignore is synced
+async fn ignore_filters(
+    src_fs: &(dyn FileSystem + Send + Sync),
+    src_tree: &Node,
+    dest_fs: &(dyn FileSystem + Send + Sync),
+    dest_tree: &Node,
+) -> anyhow::Result<[IgnoreFilter; 2]> {
+    Ok([
+        IgnoreFilter::from_tree(src_fs, src_tree).await?,
+        IgnoreFilter::from_tree(dest_fs, dest_tree).await?,
+    ])
+}
+
+fn prune(filters: &[IgnoreFilter], tree: &Node) -> Node {
+    filters
+        .iter()
+        .fold(tree.clone(), |tree, filter| filter.prune(&tree))
+}
@@ -1,5 +1,3 @@
-use std::usize;
-
 use unicode_width::UnicodeWidthStr;
 
 use crate::crustasyncfs::base::Node;
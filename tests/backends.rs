@@ -0,0 +1,112 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::Parser;
+use crustasync::cli::CLIOption;
+use crustasync::crustasyncfs::base::{FileSystem, Node};
+use crustasync::crustasyncfs::fs_from_location_str;
+use crustasync::crustasyncfs::memory::MemoryFileSystem;
+use crustasync::diff::{build_task_queue, process_tasks};
+use uuid::Uuid;
+
+// Round trips against real servers, run with `cargo test -- --ignored`
+// once the environment variable of the backend points to a scratch location
+
+fn listing(tree: &Node) -> Vec<(PathBuf, String)> {
+    let mut listing = tree
+        .into_iter()
+        .filter(|node| !node.path.as_os_str().is_empty())
+        .map(|node| {
+            let kind = if node.is_dir() {
+                String::from("dir")
+            } else {
+                hex::encode(&node.content_hash[..4])
+            };
+            (node.path.clone(), kind)
+        })
+        .collect::<Vec<_>>();
+    listing.sort();
+    listing
+}
+
+async fn memory_fs(files: &[(&str, &str)], dirs: &[&str]) -> Arc<MemoryFileSystem> {
+    let fs = Arc::new(MemoryFileSystem::new());
+    for dir in dirs {
+        fs.mkdir(Path::new(dir)).await.unwrap();
+    }
+    for (path, content) in files {
+        fs.write(Path::new(path), content.as_bytes()).await.unwrap();
+    }
+    fs
+}
+
+async fn sync_into(src_fs: Arc<MemoryFileSystem>, dst_fs: Arc<dyn FileSystem + Send + Sync>) {
+    let src_tree = src_fs.build_tree().await.unwrap();
+    let dst_tree = dst_fs.build_tree().await.unwrap();
+    let queues = build_task_queue(&src_tree, &dst_tree);
+    process_tasks(src_fs, dst_fs.clone(), &queues, 4)
+        .await
+        .unwrap();
+    let result_tree = dst_fs.build_tree().await.unwrap();
+    assert_eq!(
+        listing(&result_tree),
+        listing(&src_tree),
+        "tasks: {queues:#?}"
+    );
+}
+
+// Sync a few trees into a fresh directory under the location in env_var, then remove it
+async fn round_trip(env_var: &str) {
+    let parent = std::env::var(env_var).unwrap_or_else(|_| panic!("{env_var} is not set"));
+    let parent = parent.trim_end_matches('/');
+    let opt = CLIOption::parse_from(["crustasync", parent, parent]);
+    let parent_fs = fs_from_location_str(parent, &opt).await.unwrap();
+    let dir_name = format!("crustasync-test-{}", Uuid::new_v4());
+    parent_fs.mkdir(Path::new(&dir_name)).await.unwrap();
+    let location = format!("{parent}/{dir_name}");
+    let fs = fs_from_location_str(&location, &opt).await.unwrap();
+
+    let first = memory_fs(
+        &[("a.txt", "a"), ("dir/b.txt", "b"), ("dir/sub/c.txt", "c")],
+        &["empty"],
+    )
+    .await;
+    sync_into(first, fs.clone()).await;
+
+    // moves, a swap, a dir replaced by a file & a delete
+    let second = memory_fs(
+        &[
+            ("a.txt", "b"),
+            ("b.txt", "a"),
+            ("moved/sub/c.txt", "c"),
+            ("empty", "now a file"),
+        ],
+        &[],
+    )
+    .await;
+    sync_into(second, fs.clone()).await;
+    assert_eq!(fs.read(Path::new("empty")).await.unwrap(), b"now a file");
+
+    parent_fs.rm(Path::new(&dir_name)).await.unwrap();
+    let parent_tree = parent_fs.build_tree().await.unwrap();
+    assert!(parent_tree
+        .children
+        .iter()
+        .all(|child| child.name != dir_name));
+}
+
+// e.g. CRUSTASYNC_TEST_S3=s3:bucket/prefix with AWS_ENDPOINT_URL=http://localhost:9000
+// and the MinIO credentials in AWS_ACCESS_KEY_ID & AWS_SECRET_ACCESS_KEY
+#[tokio::test]
+#[ignore = "needs an S3 compatible server such as MinIO"]
+async fn s3_round_trip() {
+    round_trip("CRUSTASYNC_TEST_S3").await;
+}
+
+// e.g. CRUSTASYNC_TEST_SFTP=sftp:user@localhost:/tmp for a local OpenSSH server,
+// whose host key is in known_hosts & that accepts the default key or ssh-agent
+#[tokio::test]
+#[ignore = "needs an OpenSSH server"]
+async fn sftp_round_trip() {
+    round_trip("CRUSTASYNC_TEST_SFTP").await;
+}
@@ -0,0 +1,61 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use crustasync::crustasyncfs::base::{try_join_limited, RequestLimit};
+use crustasync::error::Result;
+use futures::future::{join, try_join_all};
+
+// Counts the futures running at once
+#[derive(Default)]
+struct InFlight {
+    current: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl InFlight {
+    async fn run(&self, i: usize) -> Result<usize> {
+        let current = self.current.fetch_add(1, Ordering::SeqCst) + 1;
+        self.max.fetch_max(current, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        self.current.fetch_sub(1, Ordering::SeqCst);
+        Ok(i)
+    }
+
+    fn max(&self) -> usize {
+        self.max.load(Ordering::SeqCst)
+    }
+}
+
+#[tokio::test]
+async fn try_join_limited_polls_at_most_limit_futures() {
+    for limit in [1, 3, 8] {
+        let in_flight = InFlight::default();
+        let results = try_join_limited((0..50).map(|i| in_flight.run(i)), limit)
+            .await
+            .unwrap();
+        assert_eq!(results, (0..50).collect::<Vec<_>>());
+        assert_eq!(in_flight.max(), limit);
+    }
+}
+
+#[tokio::test]
+async fn request_limit_is_shared_by_every_caller() {
+    let limit = RequestLimit::new(3);
+    let in_flight = InFlight::default();
+    let requests = |limit: RequestLimit| {
+        let in_flight = &in_flight;
+        try_join_all((0..20).map(move |i| {
+            let limit = limit.clone();
+            async move {
+                let _permit = limit.acquire().await;
+                in_flight.run(i).await
+            }
+        }))
+    };
+
+    // e.g. a tree walk & tasks running at once, both unbounded by themselves
+    let (a, b) = join(requests(limit.clone()), requests(limit)).await;
+    a.unwrap();
+    b.unwrap();
+    assert_eq!(in_flight.max(), 3);
+}
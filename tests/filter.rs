@@ -0,0 +1,156 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use clap::Parser;
+use crustasync::cli::CLIOption;
+use crustasync::crustasyncfs::base::{FileSystem, Node};
+use crustasync::crustasyncfs::memory::MemoryFileSystem;
+use crustasync::diff::{
+    build_pinned_task_queue, pin_dirs, pinned_dirs, process_tasks, restore_pruned,
+};
+use crustasync::filter::TreeFilter;
+
+fn tree_filter(args: &[&str]) -> TreeFilter {
+    let opt = CLIOption::parse_from(["crustasync", "src", "dst"].iter().chain(args));
+    TreeFilter::new(&opt).unwrap()
+}
+
+async fn memory_fs(files: &[(&str, &str)], dirs: &[&str]) -> Arc<MemoryFileSystem> {
+    let fs = Arc::new(MemoryFileSystem::new());
+    for dir in dirs {
+        fs.mkdir(Path::new(dir)).await.unwrap();
+    }
+    for (path, content) in files {
+        fs.write(Path::new(path), content.as_bytes()).await.unwrap();
+    }
+    fs
+}
+
+fn paths(tree: &Node) -> Vec<String> {
+    let mut paths = tree
+        .into_iter()
+        .filter(|node| !node.path.as_os_str().is_empty())
+        .map(|node| node.path.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+#[tokio::test]
+async fn exclude_takes_precedence_over_include() {
+    let fs = memory_fs(
+        &[
+            ("a.txt", ""),
+            ("b.pdf", ""),
+            ("docs/c.txt", ""),
+            ("docs/d.pdf", ""),
+            ("docs/draft.txt", ""),
+            ("secret/e.txt", ""),
+        ],
+        &[],
+    )
+    .await;
+    let tree = fs.build_tree().await.unwrap();
+
+    let filter = tree_filter(&["--include", "*.txt"]);
+    assert_eq!(
+        paths(&filter.prune_src(&tree)),
+        [
+            "a.txt",
+            "docs",
+            "docs/c.txt",
+            "docs/draft.txt",
+            "secret",
+            "secret/e.txt"
+        ]
+    );
+
+    let filter = tree_filter(&[
+        "--include",
+        "*.txt",
+        "--exclude",
+        "secret",
+        "--exclude",
+        "**/draft.*",
+    ]);
+    assert_eq!(
+        paths(&filter.prune_src(&tree)),
+        ["a.txt", "docs", "docs/c.txt"]
+    );
+}
+
+#[tokio::test]
+async fn include_drops_empty_dirs() {
+    let fs = memory_fs(&[("a.txt", ""), ("docs/b.pdf", "")], &["empty"]).await;
+    let tree = fs.build_tree().await.unwrap();
+
+    assert_eq!(
+        paths(&tree_filter(&["--exclude", "*.pdf"]).prune_src(&tree)),
+        ["a.txt", "empty"]
+    );
+    assert_eq!(
+        paths(&tree_filter(&["--include", "*.txt"]).prune_src(&tree)),
+        ["a.txt"]
+    );
+}
+
+#[tokio::test]
+async fn size_and_time_filters_only_apply_to_files() {
+    let fs = memory_fs(
+        &[("1", "x"), ("2", "xx"), ("3", "xxx"), ("dir/4", "xxxx")],
+        &[],
+    )
+    .await;
+    let tree = fs.build_tree().await.unwrap();
+
+    let filter = tree_filter(&["--min-size", "2", "--max-size", "3"]);
+    assert_eq!(paths(&filter.prune_src(&tree)), ["2", "3"]);
+    let filter = tree_filter(&["--newer-than", "1h"]);
+    assert_eq!(paths(&filter.prune_src(&tree)), paths(&tree));
+    let filter = tree_filter(&["--newer-than", "2999-01-01"]);
+    assert!(paths(&filter.prune_src(&tree)).is_empty());
+}
+
+#[tokio::test]
+async fn other_trees_follow_the_source_files() {
+    let src_fs = memory_fs(&[("big", "big content"), ("small", "s")], &[]).await;
+    let dst_fs = memory_fs(&[("big", "b"), ("small", "small content")], &[]).await;
+    let src_tree = src_fs.build_tree().await.unwrap();
+    let dst_tree = dst_fs.build_tree().await.unwrap();
+
+    // big is left alone rather than deleted from dst, small is updated
+    let filter = tree_filter(&["--max-size", "5"]);
+    assert_eq!(paths(&filter.prune_other(&src_tree, &dst_tree)), ["small"]);
+}
+
+#[tokio::test]
+async fn filtered_out_files_survive_the_removal_of_their_dir() {
+    let src_fs = memory_fs(&[("a.txt", "a")], &[]).await;
+    let dst_fs = memory_fs(
+        &[("a.txt", "a"), ("d/big", "big content"), ("d/x", "x")],
+        &[],
+    )
+    .await;
+    let src_tree = src_fs.build_tree().await.unwrap();
+    let full_dst_tree = dst_fs.build_tree().await.unwrap();
+
+    let filter = tree_filter(&["--max-size", "5"]);
+    let dst_tree = filter.prune_other(&src_tree, &full_dst_tree);
+    let src_tree = filter.prune_src(&src_tree);
+    let pinned = pinned_dirs(&full_dst_tree, &dst_tree);
+    let src_tree = pin_dirs(&src_tree, &pinned);
+    let queues = build_pinned_task_queue(&src_tree, &dst_tree, &pinned);
+    process_tasks(src_fs, dst_fs.clone(), &queues, 4)
+        .await
+        .unwrap();
+
+    let result = dst_fs.build_tree().await.unwrap();
+    assert_eq!(
+        paths(&result),
+        ["a.txt", "d", "d/big"],
+        "tasks: {queues:#?}"
+    );
+    // the stored tree still has the filtered out files
+    let synced_tree = restore_pruned(&src_tree, &full_dst_tree, &dst_tree);
+    assert_eq!(paths(&synced_tree), paths(&result));
+}
@@ -0,0 +1,537 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use crustasync::crustasyncfs::base::{FileSystem, IgnoreFilter, Node, CRUSTASYNC_IGNORE_FILE};
+use crustasync::crustasyncfs::local::LocalFileSystem;
+use crustasync::crustasyncfs::memory::MemoryFileSystem;
+use crustasync::diff::{
+    build_pinned_task_queue, build_task_queue, build_two_way_task_queue, pinned_dirs,
+    process_tasks, process_two_way_tasks, ConflictPolicy, ConflictResolution, Task,
+};
+use crustasync::error::Error;
+use proptest::collection::btree_map;
+use proptest::prelude::*;
+use uuid::Uuid;
+
+// Random trees use few names & contents,
+// so that moves, swaps, duplicated content and file <-> dir changes are common
+
+#[derive(Debug, Clone)]
+enum Entry {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, Entry>),
+}
+
+fn name() -> impl Strategy<Value = String> {
+    prop_oneof![Just("a"), Just("b"), Just("c")].prop_map(String::from)
+}
+
+fn content() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![Just(""), Just("x"), Just("y")].prop_map(|s| s.as_bytes().to_vec())
+}
+
+fn entry() -> impl Strategy<Value = Entry> {
+    let leaf = prop_oneof![
+        content().prop_map(Entry::File),
+        Just(Entry::Dir(BTreeMap::new())),
+    ];
+    leaf.prop_recursive(3, 24, 3, |inner| {
+        prop_oneof![
+            content().prop_map(Entry::File),
+            btree_map(name(), inner, 0..=3).prop_map(Entry::Dir),
+        ]
+    })
+}
+
+fn tree() -> impl Strategy<Value = BTreeMap<String, Entry>> {
+    btree_map(name(), entry(), 0..=3)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Src,
+    Dst,
+}
+
+// Top level entries replaced (Some) or deleted (None) on one side only, so edits never conflict
+fn edits() -> impl Strategy<Value = BTreeMap<String, (Side, Option<Entry>)>> {
+    let side = prop_oneof![Just(Side::Src), Just(Side::Dst)];
+    btree_map(name(), (side, proptest::option::of(entry())), 0..=3)
+}
+
+fn apply_edits(
+    base: &BTreeMap<String, Entry>,
+    edits: &BTreeMap<String, (Side, Option<Entry>)>,
+    sides: &[Side],
+) -> BTreeMap<String, Entry> {
+    let mut tree = base.clone();
+    for (name, (side, edit)) in edits {
+        if !sides.contains(side) {
+            continue;
+        }
+        match edit {
+            Some(entry) => tree.insert(name.clone(), entry.clone()),
+            None => tree.remove(name),
+        };
+    }
+    tree
+}
+
+async fn populate(fs: &MemoryFileSystem, parent: &Path, entries: &BTreeMap<String, Entry>) {
+    for (name, entry) in entries {
+        let path = parent.join(name);
+        match entry {
+            Entry::File(content) => fs.write(&path, content).await.unwrap(),
+            Entry::Dir(children) => {
+                fs.mkdir(&path).await.unwrap();
+                Box::pin(populate(fs, &path, children)).await;
+            }
+        }
+    }
+}
+
+// Flat listing, to get readable failure messages
+fn listing(tree: &Node) -> Vec<(PathBuf, String)> {
+    let mut listing = tree
+        .into_iter()
+        .map(|node| {
+            let kind = if node.is_dir() {
+                String::from("dir")
+            } else {
+                hex::encode(&node.content_hash[..4])
+            };
+            (node.path.clone(), kind)
+        })
+        .collect::<Vec<_>>();
+    listing.sort();
+    listing
+}
+
+async fn memory_fs(entries: &BTreeMap<String, Entry>) -> Arc<MemoryFileSystem> {
+    let fs = Arc::new(MemoryFileSystem::new());
+    populate(&fs, Path::new(""), entries).await;
+    fs
+}
+
+async fn sync(
+    src: &BTreeMap<String, Entry>,
+    dst: &BTreeMap<String, Entry>,
+) -> Result<(), TestCaseError> {
+    let src_fs = memory_fs(src).await;
+    let dst_fs = memory_fs(dst).await;
+
+    let src_tree = src_fs.build_tree().await.unwrap();
+    let dst_tree = dst_fs.build_tree().await.unwrap();
+    let queues = build_task_queue(&src_tree, &dst_tree);
+
+    let res = process_tasks(src_fs.clone(), dst_fs.clone(), &queues, 4).await;
+    prop_assert!(res.is_ok(), "{:?} while processing {:#?}", res, queues);
+
+    let result_tree = dst_fs.build_tree().await.unwrap();
+    prop_assert_eq!(
+        listing(&result_tree),
+        listing(&src_tree),
+        "tasks: {:#?}",
+        queues
+    );
+    prop_assert_eq!(result_tree.content_hash, src_tree.content_hash);
+    Ok(())
+}
+
+// Two-way sync of edits made on both sides since base,
+// checking that both sides end up as expected and agree with the new base
+async fn sync_two_way(
+    base: &BTreeMap<String, Entry>,
+    edits: &BTreeMap<String, (Side, Option<Entry>)>,
+) -> Result<(), TestCaseError> {
+    let base_tree = memory_fs(base).await.build_tree().await.unwrap();
+    let src_fs = memory_fs(&apply_edits(base, edits, &[Side::Src])).await;
+    let dst_fs = memory_fs(&apply_edits(base, edits, &[Side::Dst])).await;
+    let expected_tree = memory_fs(&apply_edits(base, edits, &[Side::Src, Side::Dst]))
+        .await
+        .build_tree()
+        .await
+        .unwrap();
+
+    let src_tree = src_fs.build_tree().await.unwrap();
+    let dst_tree = dst_fs.build_tree().await.unwrap();
+    let plan = build_two_way_task_queue(
+        &base_tree,
+        &src_tree,
+        &dst_tree,
+        ConflictPolicy::Abort,
+        &[],
+        &[],
+    );
+    prop_assert!(plan.conflicts.is_empty(), "plan: {:#?}", plan);
+
+    let res = process_two_way_tasks(src_fs.clone(), dst_fs.clone(), &plan, 4).await;
+    prop_assert!(res.is_ok(), "{:?} while processing {:#?}", res, plan);
+
+    let src_result = src_fs.build_tree().await.unwrap();
+    let dst_result = dst_fs.build_tree().await.unwrap();
+    prop_assert_eq!(
+        listing(&src_result),
+        listing(&expected_tree),
+        "plan: {:#?}",
+        plan
+    );
+    prop_assert_eq!(
+        listing(&dst_result),
+        listing(&expected_tree),
+        "plan: {:#?}",
+        plan
+    );
+    prop_assert_eq!(
+        listing(&plan.base_tree),
+        listing(&expected_tree),
+        "plan: {:#?}",
+        plan
+    );
+
+    // nothing left to do on the next run
+    let next_plan = build_two_way_task_queue(
+        &plan.base_tree,
+        &src_result,
+        &dst_result,
+        ConflictPolicy::Abort,
+        &[],
+        &[],
+    );
+    prop_assert!(
+        next_plan
+            .src_queues
+            .iter()
+            .chain(&next_plan.dst_queues)
+            .all(Vec::is_empty),
+        "next plan: {:#?}",
+        next_plan
+    );
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(1000))]
+
+    #[test]
+    fn plan_turns_dst_into_src(src in tree(), dst in tree()) {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(sync(&src, &dst))?;
+    }
+
+    #[test]
+    fn two_way_sync_merges_edits_of_both_sides(base in tree(), edits in edits()) {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        runtime.block_on(sync_two_way(&base, &edits))?;
+    }
+}
+
+fn files(entries: &[(&str, &str)]) -> BTreeMap<String, Entry> {
+    entries
+        .iter()
+        .map(|(name, content)| (name.to_string(), Entry::File(content.as_bytes().to_vec())))
+        .collect()
+}
+
+fn set_updated_at(tree: &mut Node, path: &str, updated_at: DateTime<Utc>) {
+    for node in tree.children.iter_mut() {
+        if node.path == Path::new(path) {
+            node.updated_at = updated_at;
+        }
+    }
+}
+
+fn resolutions(queues: &[Vec<Task>]) -> Vec<(PathBuf, ConflictResolution)> {
+    queues
+        .iter()
+        .flatten()
+        .filter_map(|task| match task {
+            Task::Conflict { path, resolution } => Some((path.clone(), resolution.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+async fn read(fs: &MemoryFileSystem, path: &str) -> String {
+    String::from_utf8(fs.read(Path::new(path)).await.unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn newest_policy_keeps_the_last_modified_side() {
+    let base_tree = memory_fs(&files(&[("a.txt", "base")]))
+        .await
+        .build_tree()
+        .await
+        .unwrap();
+    let old = DateTime::UNIX_EPOCH;
+    let new = old + TimeDelta::hours(1);
+
+    for (src_time, dst_time, expected) in [(new, old, "src"), (old, new, "dst")] {
+        let src_fs = memory_fs(&files(&[("a.txt", "src")])).await;
+        let dst_fs = memory_fs(&files(&[("a.txt", "dst")])).await;
+        let mut src_tree = src_fs.build_tree().await.unwrap();
+        let mut dst_tree = dst_fs.build_tree().await.unwrap();
+        set_updated_at(&mut src_tree, "a.txt", src_time);
+        set_updated_at(&mut dst_tree, "a.txt", dst_time);
+
+        let plan = build_two_way_task_queue(
+            &base_tree,
+            &src_tree,
+            &dst_tree,
+            ConflictPolicy::Newest,
+            &[],
+            &[],
+        );
+        assert_eq!(plan.conflicts, vec![PathBuf::from("a.txt")]);
+        process_two_way_tasks(src_fs.clone(), dst_fs.clone(), &plan, 4)
+            .await
+            .unwrap();
+
+        assert_eq!(read(&src_fs, "a.txt").await, expected);
+        assert_eq!(read(&dst_fs, "a.txt").await, expected);
+    }
+}
+
+#[tokio::test]
+async fn keep_both_policy_copies_to_a_free_name() {
+    let base_tree = memory_fs(&files(&[("a.txt", "base")]))
+        .await
+        .build_tree()
+        .await
+        .unwrap();
+    // the first copy name is taken by a file added on the source side
+    let src_fs = memory_fs(&files(&[
+        ("a.txt", "src"),
+        ("a (conflict copy).txt", "other"),
+    ]))
+    .await;
+    let dst_fs = memory_fs(&files(&[("a.txt", "dst")])).await;
+    let src_tree = src_fs.build_tree().await.unwrap();
+    let dst_tree = dst_fs.build_tree().await.unwrap();
+
+    let plan = build_two_way_task_queue(
+        &base_tree,
+        &src_tree,
+        &dst_tree,
+        ConflictPolicy::KeepBoth,
+        &[],
+        &[],
+    );
+    let copy = PathBuf::from("a (conflict copy 2).txt");
+    assert_eq!(
+        resolutions(&plan.dst_queues),
+        vec![(
+            PathBuf::from("a.txt"),
+            ConflictResolution::KeepBoth { copy: copy.clone() }
+        )]
+    );
+    process_two_way_tasks(src_fs.clone(), dst_fs.clone(), &plan, 4)
+        .await
+        .unwrap();
+
+    for fs in [&src_fs, &dst_fs] {
+        assert_eq!(read(fs, "a.txt").await, "src");
+        assert_eq!(read(fs, "a (conflict copy).txt").await, "other");
+        assert_eq!(read(fs, "a (conflict copy 2).txt").await, "dst");
+    }
+    let src_result = src_fs.build_tree().await.unwrap();
+    assert_eq!(listing(&plan.base_tree), listing(&src_result));
+}
+
+#[tokio::test]
+async fn abort_policy_fails_before_touching_either_side() {
+    let base_tree = memory_fs(&files(&[("a.txt", "base"), ("b.txt", "b")]))
+        .await
+        .build_tree()
+        .await
+        .unwrap();
+    // besides the conflict, b.txt is deleted & c.txt added on the source side
+    let src_fs = memory_fs(&files(&[("a.txt", "src"), ("c.txt", "c")])).await;
+    let dst_fs = memory_fs(&files(&[("a.txt", "dst"), ("b.txt", "b")])).await;
+    let src_tree = src_fs.build_tree().await.unwrap();
+    let dst_tree = dst_fs.build_tree().await.unwrap();
+
+    let plan = build_two_way_task_queue(
+        &base_tree,
+        &src_tree,
+        &dst_tree,
+        ConflictPolicy::Abort,
+        &[],
+        &[],
+    );
+    let res = process_two_way_tasks(src_fs.clone(), dst_fs.clone(), &plan, 4).await;
+
+    assert!(
+        matches!(&res, Err(Error::Conflict(path)) if path == Path::new("a.txt")),
+        "{res:?}"
+    );
+    assert_eq!(
+        listing(&src_fs.build_tree().await.unwrap()),
+        listing(&src_tree)
+    );
+    assert_eq!(
+        listing(&dst_fs.build_tree().await.unwrap()),
+        listing(&dst_tree)
+    );
+}
+
+// Moves forming a cycle need a temporary name, whatever proptest generates
+async fn sync_by_moves(src: &BTreeMap<String, Entry>, dst: &BTreeMap<String, Entry>) {
+    let src_tree = memory_fs(src).await.build_tree().await.unwrap();
+    let dst_tree = memory_fs(dst).await.build_tree().await.unwrap();
+    let queues = build_task_queue(&src_tree, &dst_tree);
+    assert!(
+        queues
+            .iter()
+            .flatten()
+            .all(|task| matches!(task, Task::Move { .. })),
+        "{queues:#?}"
+    );
+    sync(src, dst).await.unwrap();
+}
+
+#[tokio::test]
+async fn plan_swaps_two_files() {
+    let src = files(&[("a.txt", "b content"), ("b.txt", "a content")]);
+    let dst = files(&[("a.txt", "a content"), ("b.txt", "b content")]);
+    sync_by_moves(&src, &dst).await;
+}
+
+#[tokio::test]
+async fn plan_rotates_three_files() {
+    let src = files(&[
+        ("a.txt", "c content"),
+        ("b.txt", "a content"),
+        ("c.txt", "b content"),
+    ]);
+    let dst = files(&[
+        ("a.txt", "a content"),
+        ("b.txt", "b content"),
+        ("c.txt", "c content"),
+    ]);
+    sync_by_moves(&src, &dst).await;
+}
+
+#[tokio::test]
+async fn plan_swaps_two_dirs() {
+    let dir = |content: &str| Entry::Dir(files(&[("f.txt", content)]));
+    let src = BTreeMap::from([("a".to_string(), dir("b")), ("b".to_string(), dir("a"))]);
+    let dst = BTreeMap::from([("a".to_string(), dir("a")), ("b".to_string(), dir("b"))]);
+    sync_by_moves(&src, &dst).await;
+}
+
+// Ignored files are pruned from the trees before planning,
+// the dirs holding them must only lose their other children
+async fn ignoring_fs(paths: &[&str]) -> Arc<MemoryFileSystem> {
+    let fs = Arc::new(MemoryFileSystem::new());
+    fs.write(Path::new(CRUSTASYNC_IGNORE_FILE), b"*.log\n")
+        .await
+        .unwrap();
+    for path in paths {
+        fs.write(Path::new(path), path.as_bytes()).await.unwrap();
+    }
+    fs
+}
+
+// Tree of fs, then the same tree without its ignored nodes
+async fn ignored_trees(fs: &(impl FileSystem + ?Sized)) -> (Node, Node) {
+    let tree = fs.build_tree().await.unwrap();
+    let filter = IgnoreFilter::from_tree(fs, &tree).await.unwrap();
+    let pruned = filter.prune(&tree);
+    (tree, pruned)
+}
+
+async fn paths(fs: &(impl FileSystem + ?Sized)) -> Vec<String> {
+    let tree = fs.build_tree().await.unwrap();
+    let mut paths = tree
+        .into_iter()
+        .filter(|node| !node.path.as_os_str().is_empty())
+        .map(|node| node.path.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+}
+
+#[tokio::test]
+async fn ignored_files_survive_the_removal_of_their_dir() {
+    // d is missing from the source, e is a file there
+    let src_fs = ignoring_fs(&["e"]).await;
+    let dst_fs = ignoring_fs(&["d/keep.log", "d/sub/x.txt", "e/keep.log", "e/x.txt"]).await;
+    let (_, src_tree) = ignored_trees(&*src_fs).await;
+    let (full_dst_tree, dst_tree) = ignored_trees(&*dst_fs).await;
+
+    let pinned = pinned_dirs(&full_dst_tree, &dst_tree);
+    let queues = build_pinned_task_queue(&src_tree, &dst_tree, &pinned);
+    process_tasks(src_fs, dst_fs.clone(), &queues, 4)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        paths(&*dst_fs).await,
+        [".crustasyncignore", "d", "d/keep.log", "e", "e/keep.log"],
+        "tasks: {queues:#?}"
+    );
+}
+
+#[tokio::test]
+async fn ignored_files_survive_the_removal_of_their_dir_on_the_other_side() {
+    let base_fs = ignoring_fs(&["d/x.txt"]).await;
+    let (_, base_tree) = ignored_trees(&*base_fs).await;
+    let src_fs = ignoring_fs(&[]).await;
+    let dst_fs = ignoring_fs(&["d/keep.log", "d/x.txt"]).await;
+    let (full_src_tree, src_tree) = ignored_trees(&*src_fs).await;
+    let (full_dst_tree, dst_tree) = ignored_trees(&*dst_fs).await;
+
+    let plan = build_two_way_task_queue(
+        &base_tree,
+        &src_tree,
+        &dst_tree,
+        ConflictPolicy::Abort,
+        &pinned_dirs(&full_src_tree, &src_tree),
+        &pinned_dirs(&full_dst_tree, &dst_tree),
+    );
+    process_two_way_tasks(src_fs.clone(), dst_fs.clone(), &plan, 4)
+        .await
+        .unwrap();
+
+    // the dir is kept on both sides, so that they agree
+    assert_eq!(
+        paths(&*dst_fs).await,
+        [".crustasyncignore", "d", "d/keep.log"],
+        "plan: {plan:#?}"
+    );
+    assert_eq!(paths(&*src_fs).await, [".crustasyncignore", "d"]);
+    assert_eq!(
+        listing(&plan.base_tree),
+        listing(&src_fs.build_tree().await.unwrap())
+    );
+}
+
+// Local & GoogleDrive trees used to be pruned while being built, hiding the ignored files from the planner
+#[tokio::test]
+async fn ignored_local_files_survive_the_removal_of_their_dir() {
+    let root = std::env::temp_dir().join(format!("crustasync-test-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(root.join("d")).unwrap();
+    std::fs::write(root.join(CRUSTASYNC_IGNORE_FILE), "*.log\n").unwrap();
+    std::fs::write(root.join("d/keep.log"), "keep").unwrap();
+    std::fs::write(root.join("d/x.txt"), "x").unwrap();
+    let dst_fs = Arc::new(LocalFileSystem::new(&root).await.unwrap());
+    let src_fs = ignoring_fs(&[]).await;
+
+    let (_, src_tree) = ignored_trees(&*src_fs).await;
+    let (full_dst_tree, dst_tree) = ignored_trees(&*dst_fs).await;
+    let pinned = pinned_dirs(&full_dst_tree, &dst_tree);
+    let queues = build_pinned_task_queue(&src_tree, &dst_tree, &pinned);
+    let res = process_tasks(src_fs, dst_fs.clone(), &queues, 4).await;
+    let result = paths(&*dst_fs).await;
+    std::fs::remove_dir_all(&root).unwrap();
+
+    res.unwrap();
+    assert_eq!(
+        result,
+        [".crustasyncignore", "d", "d/keep.log"],
+        "tasks: {queues:#?}"
+    );
+}